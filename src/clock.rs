@@ -0,0 +1,48 @@
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// Monotonic time source for the engine and exit triggers. Timestamps are the time elapsed
+/// since an arbitrary, clock specific epoch, so only differences between them are meaningful.
+pub trait Clock {
+    fn now(&self) -> Duration;
+}
+
+/// Real time, unaffected by wall-clock adjustments
+#[derive(Clone, Copy, Debug)]
+pub struct MonotonicClock {
+    epoch: Instant,
+}
+
+impl Default for MonotonicClock {
+    fn default() -> Self {
+        Self {
+            epoch: Instant::now(),
+        }
+    }
+}
+
+impl Clock for MonotonicClock {
+    fn now(&self) -> Duration {
+        self.epoch.elapsed()
+    }
+}
+
+/// Time that only moves when told to, for simulations and tests. Clones share the same time,
+/// so a copy kept outside the engine can drive it.
+#[derive(Clone, Debug, Default)]
+pub struct ManualClock {
+    now: Rc<Cell<Duration>>,
+}
+
+impl ManualClock {
+    pub fn advance(&self, by: Duration) {
+        self.now.set(self.now.get() + by);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Duration {
+        self.now.get()
+    }
+}
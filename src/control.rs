@@ -0,0 +1,303 @@
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PidConfig {
+    pub kp: f64,
+    pub ki: f64,
+    pub kd: f64,
+    pub output_min: f64,
+    pub output_max: f64,
+    /// Time constant (s) of the low-pass filter on the derivative term, 0 disables it
+    pub derivative_filter: f64,
+}
+
+impl PidConfig {
+    #![allow(unused)]
+
+    pub fn new(kp: f64, ki: f64, kd: f64) -> Self {
+        Self {
+            kp,
+            ki,
+            kd,
+            output_min: 0.0,
+            output_max: 100.0,
+            derivative_filter: 0.0,
+        }
+    }
+
+    pub fn with_output_range(mut self, min: f64, max: f64) -> Self {
+        self.output_min = min;
+        self.output_max = max;
+        self
+    }
+
+    pub fn with_derivative_filter(mut self, time_constant: f64) -> Self {
+        self.derivative_filter = time_constant;
+        self
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Pid {
+    config: PidConfig,
+    integral: f64,
+    derivative: f64,
+    prev_measurement: Option<f64>,
+    prev_output: f64,
+}
+
+impl Pid {
+    pub fn new(config: PidConfig) -> Self {
+        Self {
+            config,
+            integral: 0.0,
+            derivative: 0.0,
+            prev_measurement: None,
+            prev_output: 0.0,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.integral = 0.0;
+        self.derivative = 0.0;
+        self.prev_measurement = None;
+        self.prev_output = 0.0;
+    }
+
+    pub fn update(&mut self, set_point: f64, measured: f64, dt: f64) -> f64 {
+        let c = &self.config;
+        let error = set_point - measured;
+
+        // Derivative on the measurement, so setpoint steps do not kick the output
+        let raw_derivative = match self.prev_measurement {
+            Some(prev) if dt > 0.0 => -(measured - prev) / dt,
+            _ => 0.0,
+        };
+        let alpha = if c.derivative_filter > 0.0 {
+            dt / (c.derivative_filter + dt)
+        } else {
+            1.0
+        };
+        self.derivative += alpha * (raw_derivative - self.derivative);
+        self.prev_measurement = Some(measured);
+
+        let integral = self.integral + c.ki * error * dt;
+        let unclamped = c.kp * error + integral + c.kd * self.derivative;
+        let output = unclamped.clamp(c.output_min, c.output_max);
+
+        // Anti-windup: only integrate while unsaturated or when it pulls out of saturation
+        if unclamped == output
+            || (unclamped > c.output_max && error < 0.0)
+            || (unclamped < c.output_min && error > 0.0)
+        {
+            self.integral = integral;
+        }
+
+        self.prev_output = output;
+        output
+    }
+
+    /// Another loop's lower output was applied instead of this one's, the integral is backed
+    /// off by the difference so it does not wind up while overridden
+    pub fn track(&mut self, applied: f64) {
+        if self.prev_output > applied {
+            self.integral -= self.prev_output - applied;
+            self.prev_output = applied;
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum ControlTarget {
+    Pressure,
+    Flow,
+}
+
+/// Closes the pressure and flow loops in software, turning setpoints into motor power. A limit
+/// on the other quantity runs its loop too, and the lower of both powers is applied
+#[derive(Clone, Debug)]
+pub struct SoftwareControl {
+    pressure: Pid,
+    flow: Pid,
+    last_update: Option<(ControlTarget, Duration)>,
+    pressure_limit: Option<f64>,
+    flow_limit: Option<f64>,
+}
+
+impl SoftwareControl {
+    pub fn new(pressure: PidConfig, flow: PidConfig) -> Self {
+        Self {
+            pressure: Pid::new(pressure),
+            flow: Pid::new(flow),
+            last_update: None,
+            pressure_limit: None,
+            flow_limit: None,
+        }
+    }
+
+    pub fn set_pressure_limit(&mut self, limit: Option<f64>) {
+        self.pressure_limit = limit;
+    }
+
+    pub fn set_flow_limit(&mut self, limit: Option<f64>) {
+        self.flow_limit = limit;
+    }
+
+    pub fn pressure_to_power(
+        &mut self,
+        set_point: f64,
+        pressure: f64,
+        flow: f64,
+        now: Duration,
+    ) -> f64 {
+        let dt = self.tick(ControlTarget::Pressure, now);
+        let power = self.pressure.update(set_point, pressure, dt);
+        let Some(limit) = self.flow_limit else {
+            return power;
+        };
+        let cap = self.flow.update(limit, flow, dt);
+        self.select(power, cap)
+    }
+
+    pub fn flow_to_power(
+        &mut self,
+        set_point: f64,
+        flow: f64,
+        pressure: f64,
+        now: Duration,
+    ) -> f64 {
+        let dt = self.tick(ControlTarget::Flow, now);
+        let power = self.flow.update(set_point, flow, dt);
+        let Some(limit) = self.pressure_limit else {
+            return power;
+        };
+        let cap = self.pressure.update(limit, pressure, dt);
+        self.select(power, cap)
+    }
+
+    // Applies the lower of the target and limit loop powers, the loop left out tracks it
+    fn select(&mut self, power: f64, cap: f64) -> f64 {
+        let applied = power.min(cap);
+        self.pressure.track(applied);
+        self.flow.track(applied);
+        applied
+    }
+
+    /// Called when the output is driven directly, so the next loop starts from a clean state
+    pub fn release(&mut self) {
+        self.last_update = None;
+    }
+
+    fn tick(&mut self, target: ControlTarget, now: Duration) -> f64 {
+        let dt = match self.last_update {
+            Some((t, last)) if t == target => now.saturating_sub(last).as_secs_f64(),
+            _ => {
+                self.pressure.reset();
+                self.flow.reset();
+                0.0
+            }
+        };
+        self.last_update = Some((target, now));
+        dt
+    }
+}
+
+/// How pressure and flow setpoints reach the hardware
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Debug, Default)]
+pub enum ControlMode {
+    /// The hardware closes the loop on the setpoints itself
+    #[default]
+    Hardware,
+    /// The crate runs PID loops and only commands motor power
+    Software(SoftwareControl),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DT: Duration = Duration::from_millis(10);
+
+    /// A puck where pressure and flow follow the motor power with a lag
+    #[derive(Default)]
+    struct Puck {
+        pressure: f64,
+        flow: f64,
+    }
+
+    impl Puck {
+        fn step(&mut self, power: f64) {
+            let alpha = DT.as_secs_f64() / 0.5;
+            self.pressure += (0.1 * power - self.pressure) * alpha;
+            self.flow += (0.08 * power - self.flow) * alpha;
+        }
+    }
+
+    fn control() -> SoftwareControl {
+        let pid = PidConfig::new(5.0, 20.0, 0.0);
+        SoftwareControl::new(pid, pid)
+    }
+
+    /// Holds the pressure target for `seconds`, returning the power last applied
+    fn hold_pressure(
+        c: &mut SoftwareControl,
+        puck: &mut Puck,
+        now: &mut Duration,
+        target: f64,
+        seconds: u32,
+    ) -> f64 {
+        let mut power = 0.0;
+        for _ in 0..seconds * 100 {
+            power = c.pressure_to_power(target, puck.pressure, puck.flow, *now);
+            puck.step(power);
+            *now += DT;
+        }
+        power
+    }
+
+    #[test]
+    fn pid_settles_on_the_set_point() {
+        let mut c = control();
+        let mut puck = Puck::default();
+        let mut now = Duration::ZERO;
+        let power = hold_pressure(&mut c, &mut puck, &mut now, 9.0, 20);
+        assert!((puck.pressure - 9.0).abs() < 0.05, "{}", puck.pressure);
+        assert!((power - 90.0).abs() < 0.5, "{power}");
+    }
+
+    #[test]
+    fn flow_limit_caps_pressure_control() {
+        let mut c = control();
+        let mut puck = Puck::default();
+        let mut now = Duration::ZERO;
+
+        c.set_flow_limit(Some(4.0));
+        let capped = hold_pressure(&mut c, &mut puck, &mut now, 9.0, 20);
+        assert!((puck.flow - 4.0).abs() < 0.05, "{}", puck.flow);
+        assert!((puck.pressure - 5.0).abs() < 0.05, "{}", puck.pressure);
+
+        // The overridden pressure loop did not wind up, the power does not jump once released
+        c.set_flow_limit(None);
+        let released = c.pressure_to_power(9.0, puck.pressure, puck.flow, now);
+        assert!(released - capped < 25.0, "{capped} -> {released}");
+        hold_pressure(&mut c, &mut puck, &mut now, 9.0, 20);
+        assert!((puck.pressure - 9.0).abs() < 0.05, "{}", puck.pressure);
+    }
+
+    #[test]
+    fn pressure_limit_caps_flow_control() {
+        let mut c = control();
+        let mut puck = Puck::default();
+        let mut now = Duration::ZERO;
+
+        c.set_pressure_limit(Some(6.0));
+        for _ in 0..2000 {
+            let power = c.flow_to_power(7.0, puck.flow, puck.pressure, now);
+            puck.step(power);
+            now += DT;
+        }
+        assert!((puck.pressure - 6.0).abs() < 0.05, "{}", puck.pressure);
+        assert!(puck.flow < 7.0 * 0.75, "{}", puck.flow);
+    }
+}
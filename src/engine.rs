@@ -1,8 +1,13 @@
 use crate::engine::EngineStepResult::{Finished, Next};
-use crate::profile::dynamics::{ControlType, InputType, Limit};
-use crate::profile::{Flow, Pressure, Profile, StageVariables};
-use crate::sensor::{Driver, SensorState};
-use std::time::SystemTime;
+use crate::profile::dynamics::{ControlType, DynamicsInputs, LimitType};
+use crate::profile::exit_trigger::ExitTrigger;
+use crate::profile::{
+    Flow, Pressure, Profile, StageExitReason, StageLog, StageVariables, Temp,
+};
+use crate::recorder::{SensorSample, Setpoints, ShotRecording, ShotSample};
+use crate::clock::{Clock, MonotonicClock};
+use crate::sensor::{Driver, LimitEnforcement, SensorState};
+use std::time::Duration;
 
 #[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ProfileState {
@@ -13,29 +18,191 @@ pub enum ProfileState {
     Retracting,
     Brewing,
     Done,
-    #[allow(unused)]
     Purging,
 }
 
-pub enum EngineStepResult<'a, T: SensorState> {
-    Next(ProfileEngineRunning<'a, T>),
-    Finished(ProfileEngineIdle<'a, T>),
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EndReason {
+    FinalWeight,
+    StageEnd,
+}
+
+/// Everything the engine reports while running a profile. Timestamps are from the engine clock
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EngineEvent {
+    StateChanged {
+        from: ProfileState,
+        to: ProfileState,
+    },
+    StageEntered {
+        stage: u8,
+        time: Duration,
+    },
+    StageExited {
+        stage: u8,
+        time: Duration,
+        reason: StageExitReason,
+    },
+    TriggerFired {
+        stage: u8,
+        trigger: ExitTrigger,
+        target_stage: u8,
+    },
+    /// The output sent to the driver differs from the one sent on the previous tick
+    SetpointChanged {
+        ctrl: ControlType,
+        input: f64,
+        value: f64,
+        time: Duration,
+    },
+    /// A limit started holding back the output, sent again only once it was released
+    LimitConstraining {
+        kind: LimitType,
+        measured: f64,
+        limit: f64,
+    },
+    LimitReleased {
+        kind: LimitType,
+    },
+    ProfileEnded(EndReason),
+    Paused {
+        time: Duration,
+    },
+    Resumed {
+        time: Duration,
+        paused_for: Duration,
+    },
+    Aborted {
+        state: ProfileState,
+        time: Duration,
+    },
+    PurgeTimedOut {
+        piston_position: f64,
+        time: Duration,
+    },
+    Finished,
     Error(&'static str),
 }
 
-pub struct ProfileEngineRunning<'a, T: SensorState> {
+pub trait EngineObserver {
+    fn on_event(&mut self, event: &EngineEvent);
+}
+
+impl<F: FnMut(&EngineEvent)> EngineObserver for F {
+    fn on_event(&mut self, event: &EngineEvent) {
+        self(event)
+    }
+}
+
+/// How the piston empties the chamber once a shot is done
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PurgeSettings {
+    /// Piston position to reach (%)
+    piston_position: f64,
+    /// Piston speed limit while purging. When unset the profile's default piston speed limit
+    /// applies, or the machine default if the profile has none
+    speed: Option<f64>,
+    /// The purge ends after this long even if the piston did not get there
+    timeout: Duration,
+}
+
+impl Default for PurgeSettings {
+    fn default() -> Self {
+        Self {
+            piston_position: 100.0,
+            speed: None,
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl PurgeSettings {
+    pub fn with_piston_position(mut self, piston_position: f64) -> Self {
+        self.piston_position = piston_position;
+        self
+    }
+
+    pub fn with_speed(mut self, speed: f64) -> Self {
+        self.speed = Some(speed);
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+/// A stage of the running profile, by position or by name
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StageRef<'s> {
+    Index(u8),
+    Name(&'s str),
+}
+
+impl From<u8> for StageRef<'_> {
+    fn from(index: u8) -> Self {
+        StageRef::Index(index)
+    }
+}
+
+impl<'s> From<&'s str> for StageRef<'s> {
+    fn from(name: &'s str) -> Self {
+        StageRef::Name(name)
+    }
+}
+
+// The engine is moved through every step by value, boxing it would allocate each tick
+#[allow(clippy::large_enum_variant)]
+pub enum EngineStepResult<'a, T: SensorState, C: Clock = MonotonicClock> {
+    Next(ProfileEngineRunning<'a, T, C>),
+    Finished(ProfileEngineIdle<'a, T, C>),
+    Error(&'static str),
+}
+
+pub struct ProfileEngineRunning<'a, T: SensorState, C: Clock = MonotonicClock> {
     driver: Driver<T>,
     profile: &'a mut Profile,
+    clock: C,
+    observers: Vec<Box<dyn EngineObserver + 'a>>,
 
-    profile_start_time: SystemTime,
-    stage_start_time: SystemTime,
+    // Timestamps from `clock`
+    profile_start_time: Duration,
+    stage_start_time: Duration,
     state: ProfileState,
     current_stage_id: u8,
+
+    // Water pumped since brewing started, integrated from the measured flow (ml)
+    dispensed_volume: f64,
+    last_step_time: Duration,
+    // Bitmask of the `LimitType`s currently sent to the driver
+    active_limits: u8,
+    // Bitmask of the `LimitType`s currently backing off the output
+    constraining: u8,
+
+    // Last setpoint sent and where the current stage's transition blend starts from
+    last_setpoint: Option<(ControlType, f64, Duration)>,
+    blend_from: Option<f64>,
+
+    recording: Option<ShotRecording>,
+    // What is commanded on the driver, and this tick's dynamics input and output
+    setpoints: Setpoints,
+    sampled: Option<(f64, f64)>,
+
+    // When the running shot was paused, the state machine does not advance until resumed
+    paused_at: Option<Duration>,
+
+    purge: PurgeSettings,
+    purge_start_time: Option<Duration>,
 }
 
-pub struct ProfileEngineIdle<'a, T: SensorState> {
+pub struct ProfileEngineIdle<'a, T: SensorState, C: Clock = MonotonicClock> {
     driver: Driver<T>,
     profile: &'a mut Profile,
+    clock: C,
+    observers: Vec<Box<dyn EngineObserver + 'a>>,
+    recording: Option<ShotRecording>,
+    purge: PurgeSettings,
 }
 
 impl<'a, T: SensorState> ProfileEngineIdle<'a, T> {
@@ -44,25 +211,102 @@ impl<'a, T: SensorState> ProfileEngineIdle<'a, T> {
             return Err("Profile with no states is not allowed");
         }
 
-        Ok(Self { profile, driver })
+        Ok(Self {
+            profile,
+            driver,
+            clock: MonotonicClock::default(),
+            observers: vec![],
+            recording: None,
+            purge: PurgeSettings::default(),
+        })
+    }
+}
+
+impl<'a, T: SensorState, C: Clock> ProfileEngineIdle<'a, T, C> {
+    /// Replaces the time source of the engine and its exit triggers
+    pub fn with_clock<N: Clock>(self, clock: N) -> ProfileEngineIdle<'a, T, N> {
+        ProfileEngineIdle {
+            driver: self.driver,
+            profile: self.profile,
+            clock,
+            observers: self.observers,
+            recording: self.recording,
+            purge: self.purge,
+        }
+    }
+
+    /// Replaces how the chamber is purged after the shot, automatically when the profile asks
+    /// for it or on `ProfileEngineRunning::purge`
+    pub fn with_purge(mut self, purge: PurgeSettings) -> Self {
+        self.purge = purge;
+        self
     }
 
-    pub fn start(self) -> ProfileEngineRunning<'a, T> {
+    /// Records every engine tick of the next shot, up to `capacity` samples
+    pub fn with_recording(mut self, capacity: usize) -> Self {
+        self.recording = Some(ShotRecording::with_capacity(capacity));
+        self
+    }
+
+    /// The recording of the finished shot, if recording was enabled. Take it before starting
+    /// the next shot, starting clears it
+    pub fn take_recording(&mut self) -> Option<ShotRecording> {
+        self.recording.take()
+    }
+
+    /// Registers an observer notified of every `EngineEvent` while the engine runs
+    pub fn add_observer(&mut self, observer: impl EngineObserver + 'a) {
+        self.observers.push(Box::new(observer));
+    }
+
+    /// Starts a new shot. The stage logs and recording of a previous shot are cleared, so an
+    /// engine returned by `abort` or a finished shot can be started again
+    pub fn start(mut self) -> ProfileEngineRunning<'a, T, C> {
+        let now = self.clock.now();
+        for log in self.profile.get_stage_logs_mut() {
+            *log = StageLog::default();
+        }
+        if let Some(recording) = &mut self.recording {
+            recording.clear();
+        }
         ProfileEngineRunning {
             driver: self.driver,
             profile: self.profile,
-            profile_start_time: SystemTime::now(),
-            stage_start_time: SystemTime::now(),
+            clock: self.clock,
+            observers: self.observers,
+            profile_start_time: now,
+            stage_start_time: now,
             state: ProfileState::Heating,
             current_stage_id: 0,
+            dispensed_volume: 0.0,
+            last_step_time: now,
+            active_limits: 0,
+            constraining: 0,
+            last_setpoint: None,
+            blend_from: None,
+            recording: self.recording,
+            setpoints: Setpoints::default(),
+            sampled: None,
+            paused_at: None,
+            purge: self.purge,
+            purge_start_time: None,
         }
     }
 }
 
-impl<'a, T: SensorState> ProfileEngineRunning<'a, T> {
-    pub fn step(mut self) -> EngineStepResult<'a, T> {
+impl<'a, T: SensorState, C: Clock> ProfileEngineRunning<'a, T, C> {
+    pub fn step(mut self) -> EngineStepResult<'a, T, C> {
         use ProfileState as PS;
 
+        self.driver.update_time(self.clock.now());
+        let previous_state = self.state;
+        self.sampled = None;
+
+        if self.paused_at.is_some() {
+            self.record_sample();
+            return Next(self);
+        }
+
         match self.state {
             PS::Start => {
                 self.state = PS::Heating;
@@ -70,6 +314,7 @@ impl<'a, T: SensorState> ProfileEngineRunning<'a, T> {
             PS::Heating => {
                 self.driver
                     .set_target_temperature(self.profile.get_starting_temp());
+                self.setpoints.temperature = Some(self.profile.get_starting_temp().into());
                 self.driver
                     .set_target_weight(self.profile.get_target_weight());
                 // NOTE: Maybe it should be a while or async to wait for the
@@ -85,39 +330,277 @@ impl<'a, T: SensorState> ProfileEngineRunning<'a, T> {
                 }
             }
             PS::Retracting => {
+                // No stage is running yet, the profile defaults protect the puck
+                self.apply_default_limits();
                 self.driver.set_target_piston_position(0.0);
+                self.setpoints.output = Some((ControlType::PistonPosition, 0.0));
                 if self.driver.sensor_data().piston_position() <= 1.0 {
+                    let now = self.clock.now();
                     self.state = PS::Brewing;
-                    self.profile_start_time = SystemTime::now();
-                    self.stage_start_time = SystemTime::now();
+                    self.profile_start_time = now;
+                    self.stage_start_time = now;
+                    self.dispensed_volume = 0.0;
+                    self.last_step_time = now;
+                    // Nothing to blend from yet, but the ramp rates apply from the measured value
+                    let ctrl = self.profile.get_stages()[0].ctrl();
+                    self.last_setpoint = self.measured(ctrl).map(|v| (ctrl, v, now));
+                    self.blend_from = None;
                 }
             }
             PS::Brewing => {
                 self.state = match self.process_stage_step() {
                     Ok(s) => s,
-                    Err(e) => return EngineStepResult::Error(e),
+                    Err(e) => {
+                        self.emit(EngineEvent::Error(e));
+                        return EngineStepResult::Error(e);
+                    }
                 };
+                if self.state != PS::Brewing {
+                    self.clear_all_limits();
+                }
             }
             PS::Done => {
                 if self.profile.auto_purge() {
-                    self.state = PS::Retracting;
+                    self.start_purge();
                 }
             }
             PS::Purging => {
-                self.driver.set_target_piston_position(100.0);
-                if self.driver.sensor_data().piston_position() >= 99.0 {
-                    return Finished(ProfileEngineIdle {
-                        profile: self.profile,
-                        driver: self.driver,
-                    });
+                let target = self.purge.piston_position;
+                self.driver.set_target_piston_position(target);
+                self.setpoints.output = Some((ControlType::PistonPosition, target));
+
+                let now = self.clock.now();
+                let position = self.driver.sensor_data().piston_position();
+                let timed_out = self
+                    .purge_start_time
+                    .is_some_and(|start| now.saturating_sub(start) >= self.purge.timeout);
+                if (position - target).abs() <= 1.0 || timed_out {
+                    if timed_out {
+                        self.emit(EngineEvent::PurgeTimedOut {
+                            piston_position: position,
+                            time: now,
+                        });
+                    }
+                    self.clear_all_limits();
+                    self.record_sample();
+                    self.emit(EngineEvent::Finished);
+                    return Finished(self.into_idle());
                 }
             }
         }
 
+        if self.state != previous_state {
+            self.emit(EngineEvent::StateChanged {
+                from: previous_state,
+                to: self.state,
+            });
+        }
+
+        self.record_sample();
         Next(self)
     }
 
-    fn save_stage_log(&mut self, _exit_time: Option<SystemTime>) {
+    /// Stops the pump and holds the state machine, stage and profile timers stop counting
+    pub fn pause(&mut self) {
+        if self.paused_at.is_some() {
+            return;
+        }
+        let now = self.clock.now();
+        self.paused_at = Some(now);
+        self.stop_outputs();
+        self.emit(EngineEvent::Paused { time: now });
+    }
+
+    /// Continues a paused shot where it stopped, as if the pause never happened
+    pub fn resume(&mut self) {
+        let Some(paused_at) = self.paused_at.take() else {
+            return;
+        };
+        let now = self.clock.now();
+        let paused_for = now.saturating_sub(paused_at);
+        self.profile_start_time += paused_for;
+        self.stage_start_time += paused_for;
+        self.last_step_time += paused_for;
+        if let Some((_, _, time)) = &mut self.last_setpoint {
+            *time += paused_for;
+        }
+        if let Some(time) = &mut self.purge_start_time {
+            *time += paused_for;
+        }
+        self.emit(EngineEvent::Resumed {
+            time: now,
+            paused_for,
+        });
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused_at.is_some()
+    }
+
+    /// Stops the shot immediately, the pump is stopped and every limit cleared. A stage being
+    /// brewed gets its exit logged as aborted, even when it was entered on this very tick
+    pub fn abort(mut self) -> ProfileEngineIdle<'a, T, C> {
+        let now = self.clock.now();
+        self.driver.update_time(now);
+        if self.state == ProfileState::Brewing {
+            if !self.profile.get_stage_logs()[self.current_stage_id as usize].is_valid() {
+                self.save_stage_log(None);
+            }
+            self.save_stage_log(Some(StageExitReason::Aborted));
+        }
+        self.stop_outputs();
+        self.clear_all_limits();
+        self.sampled = None;
+        self.record_sample();
+        self.emit(EngineEvent::Aborted {
+            state: self.state,
+            time: now,
+        });
+        self.into_idle()
+    }
+
+    /// Purges the chamber now, before a shot is started or once it is done. Not while paused,
+    /// resuming would shift the purge timeout
+    pub fn purge(&mut self) -> Result<(), &'static str> {
+        use ProfileState as PS;
+        let from = self.state;
+        match from {
+            PS::Purging => return Ok(()),
+            _ if self.paused_at.is_some() => return Err("Purging is not possible while paused"),
+            PS::Ready | PS::Done => {}
+            _ => return Err("Purging is only possible before or after a shot"),
+        }
+
+        self.driver.update_time(self.clock.now());
+        self.start_purge();
+        self.emit(EngineEvent::StateChanged {
+            from,
+            to: self.state,
+        });
+        Ok(())
+    }
+
+    fn start_purge(&mut self) {
+        self.state = ProfileState::Purging;
+        self.purge_start_time = Some(self.clock.now());
+        let mut values = [None; LimitType::ALL.len()];
+        values[LimitType::PistonSpeed as usize] = self
+            .purge
+            .speed
+            .or_else(|| self.default_limit(LimitType::PistonSpeed));
+        self.send_limits(&values);
+    }
+
+    /// Leaves the current stage for the next one, ending the shot after the last stage. Stages
+    /// cannot be changed while paused, resume first
+    pub fn skip_stage(&mut self) -> Result<(), &'static str> {
+        self.manual_transition(self.current_stage_id + 1, StageExitReason::Skipped)
+    }
+
+    /// Leaves the current stage for any stage of the profile. A stage that was already brewed
+    /// is entered again, its log keeps the earlier visits
+    pub fn goto_stage<'s>(
+        &mut self,
+        stage: impl Into<StageRef<'s>>,
+    ) -> Result<(), &'static str> {
+        let stages = self.profile.get_stages();
+        let target = match stage.into() {
+            StageRef::Index(i) if (i as usize) < stages.len() => i,
+            StageRef::Index(_) => return Err("Stage index out of range"),
+            StageRef::Name(name) => stages
+                .iter()
+                .position(|s| s.name() == name)
+                .ok_or("No stage with that name")? as u8,
+        };
+        self.manual_transition(target, StageExitReason::Jumped)
+    }
+
+    fn manual_transition(
+        &mut self,
+        target: u8,
+        reason: StageExitReason,
+    ) -> Result<(), &'static str> {
+        use ProfileState as PS;
+        if self.state != PS::Brewing {
+            return Err("Stages can only be changed while brewing");
+        }
+        if self.paused_at.is_some() {
+            return Err("Stages cannot be changed while paused");
+        }
+
+        let now = self.clock.now();
+        self.driver.update_time(now);
+        self.update_dispensed_volume(now);
+        if !self.profile.get_stage_logs()[self.current_stage_id as usize].is_valid() {
+            self.save_stage_log(None);
+        }
+
+        self.state = self.transition_stage(target, reason);
+        if self.state != PS::Brewing {
+            self.clear_all_limits();
+            self.emit(EngineEvent::StateChanged {
+                from: PS::Brewing,
+                to: self.state,
+            });
+        }
+        Ok(())
+    }
+
+    fn stop_outputs(&mut self) {
+        self.driver.set_target_power(0.0);
+        self.setpoints.output = Some((ControlType::Power, 0.0));
+    }
+
+    fn into_idle(self) -> ProfileEngineIdle<'a, T, C> {
+        ProfileEngineIdle {
+            profile: self.profile,
+            driver: self.driver,
+            clock: self.clock,
+            observers: self.observers,
+            recording: self.recording,
+            purge: self.purge,
+        }
+    }
+
+    fn record_sample(&mut self) {
+        let Some(recording) = &mut self.recording else {
+            return;
+        };
+        recording.push(ShotSample {
+            time: self.clock.now(),
+            state: self.state,
+            stage: self.current_stage_id,
+            sensors: SensorSample::read(self.driver.sensor_data(), self.dispensed_volume),
+            input: self.sampled.map(|(input, _)| input),
+            output: self.sampled.map(|(_, output)| output),
+            setpoints: self.setpoints,
+        });
+    }
+
+    /// The recording so far, normally taken once the shot is `Done`
+    pub fn take_recording(&mut self) -> Option<ShotRecording> {
+        self.recording.take()
+    }
+
+    fn emit(&mut self, event: EngineEvent) {
+        let level = match event {
+            EngineEvent::Error(_) => log::Level::Error,
+            EngineEvent::LimitConstraining { .. } | EngineEvent::PurgeTimedOut { .. } => {
+                log::Level::Warn
+            }
+            EngineEvent::SetpointChanged { .. } => log::Level::Trace,
+            EngineEvent::TriggerFired { .. } => log::Level::Debug,
+            _ => log::Level::Info,
+        };
+        log::log!(target: "engine", level, "{event:?}");
+
+        for observer in &mut self.observers {
+            observer.on_event(&event);
+        }
+    }
+
+    fn save_stage_log(&mut self, exit: Option<StageExitReason>) {
+        let now = self.clock.now();
         let log = self
             .profile
             .get_stage_logs_mut()
@@ -125,46 +608,44 @@ impl<'a, T: SensorState> ProfileEngineRunning<'a, T> {
             .unwrap();
         let vars = StageVariables::new(
             self.driver.sensor_data().water_flow().into(),
-            self.driver.sensor_data().piston_position().into(),
             self.driver.sensor_data().water_pressure().into(),
-            _exit_time.unwrap_or(SystemTime::now()),
+            self.driver.sensor_data().piston_position().into(),
+            now,
         );
 
-        if let Some(time) = _exit_time {
+        let stage = self.current_stage_id;
+        if let Some(reason) = exit {
             // is exiting stage
-            println!(
-                "Saving EXIT log for stage {}. Timestamp = {:?}",
-                self.current_stage_id, time
-            );
-
             log.put_exit_log(vars);
+            log.put_exit_reason(reason);
+            self.emit(EngineEvent::StageExited {
+                stage,
+                time: now,
+                reason,
+            });
         } else {
             // is entry stage
-            let now = SystemTime::now();
-            println!(
-                "Saving ENTRY log for stage {}. Timestamp = {:?}",
-                self.current_stage_id, now
-            );
-            let vars = StageVariables::new(
-                self.driver.sensor_data().water_flow().into(),
-                self.driver.sensor_data().piston_position().into(),
-                self.driver.sensor_data().water_pressure().into(),
-                now,
-            );
             log.put_entry_log(vars);
+            self.emit(EngineEvent::StageEntered { stage, time: now });
         };
     }
 
     fn process_stage_step(&mut self) -> Result<ProfileState, &'static str> {
         use ProfileState as PS;
         if self.driver.has_reached_final_weight() {
-            println!("Profile End reached via final weight hit");
+            if !self.profile.get_stage_logs()[self.current_stage_id as usize].is_valid() {
+                self.save_stage_log(None);
+            }
+            self.save_stage_log(Some(StageExitReason::FinalWeight));
+            self.emit(EngineEvent::ProfileEnded(EndReason::FinalWeight));
             return Ok(ProfileState::Done);
         }
 
-        println!("executing stage={}", self.current_stage_id);
+        log::trace!(target: "engine", "executing stage={}", self.current_stage_id);
 
-        let elapsed = self.profile_start_time.elapsed().unwrap();
+        let now = self.clock.now();
+        let elapsed = now.saturating_sub(self.profile_start_time);
+        self.update_dispensed_volume(now);
 
         {
             let stage_log = &self
@@ -177,58 +658,309 @@ impl<'a, T: SensorState> ProfileEngineRunning<'a, T> {
 
         let stage = &self.profile.get_stages()[self.current_stage_id as usize];
 
-        let exit_triggers = stage.exit_triggers();
-
-        for trigger in exit_triggers {
-            if trigger.check_cond(&self.driver, self.stage_start_time, self.profile_start_time) {
-                println!("EXIT COND");
-
-                return Ok(self.transition_stage(
-                    trigger.target_stage().unwrap_or(self.current_stage_id + 1),
-                ));
-            }
+        let fired = stage.exit_triggers().iter().copied().find(|trigger| {
+            trigger.check_cond(
+                &self.driver,
+                now,
+                self.stage_start_time,
+                self.profile_start_time,
+            )
+        });
+        if let Some(trigger) = fired {
+            let target_stage = trigger.target_stage().unwrap_or(self.current_stage_id + 1);
+            self.emit(EngineEvent::TriggerFired {
+                stage: self.current_stage_id,
+                trigger,
+                target_stage,
+            });
+            return Ok(self.transition_stage(target_stage, StageExitReason::Trigger));
         }
 
         let stage_dyn = stage.dynamics();
-        let input_ref_val = match stage_dyn.input_type() {
-            InputType::Time => elapsed.as_secs_f64(),
-            InputType::PistonPosition => self.driver.sensor_data().piston_position(),
-            InputType::Weight => self.driver.sensor_data().weight(),
-        };
-
-        let sampled_output = stage_dyn.run_interpolation(input_ref_val);
-        println!("sampled ({},{})", input_ref_val, sampled_output);
-        println!(
-            "Setting output at {:?} ms to {}",
-            elapsed.as_millis(),
-            sampled_output
-        );
+        let sensors = self.driver.sensor_data();
+        let inputs = DynamicsInputs {
+            time: elapsed.as_secs_f64(),
+            stage_time: now.saturating_sub(self.stage_start_time).as_secs_f64(),
+            piston_position: sensors.piston_position(),
+            weight: sensors.weight(),
+            pressure: sensors.water_pressure(),
+            flow: sensors.water_flow(),
+            temperature: sensors.water_temp(),
+            volume: self.dispensed_volume,
+        };
+        let input_ref_val = inputs.get(stage_dyn.input_type());
 
-        for l in stage.limits() {
-            match l {
-                Limit::Pressure(p) => self.driver.set_pressure_limit(Pressure::from(*p)), // NOTE: In C++ it is limited flow?
-                Limit::Flow(f) => self.driver.set_flow_limit(Flow::from(*f)),
+        let sampled_output = stage_dyn.run_interpolation(&inputs);
+        let sampled_output = match self.profile.get_stage_logs()
+            [self.current_stage_id as usize]
+            .get_entry()
+        {
+            Some(entry) => {
+                let entry_value = match stage.ctrl() {
+                    ControlType::Pressure => entry.get_pressure().into(),
+                    ControlType::Flow => entry.get_flow().into(),
+                    ControlType::PistonPosition => entry.get_piston_pos().into(),
+                    // Rejected when parsing, there is no measured power to be relative to
+                    ControlType::Power => 0.0,
+                };
+                stage_dyn
+                    .output_reference()
+                    .apply(sampled_output, entry_value)
             }
+            None => sampled_output,
+        };
+        self.sampled = Some((input_ref_val, sampled_output));
+
+        if let Some(temperature) = stage.temperature() {
+            let temperature = temperature.value(&inputs);
+            self.driver.set_target_temperature(Temp::from(temperature));
+            self.setpoints.temperature = Some(temperature);
+        }
+
+        let ctrl = stage.ctrl();
+        let sampled_output = self.smooth_setpoint(ctrl, sampled_output, inputs.stage_time);
+        let limit_values = self.apply_limits(&inputs);
+        let sampled_output = self.enforce_limits(ctrl, sampled_output, &limit_values);
+        if self.setpoints.output != Some((ctrl, sampled_output)) {
+            self.emit(EngineEvent::SetpointChanged {
+                ctrl,
+                input: input_ref_val,
+                value: sampled_output,
+                time: now,
+            });
         }
 
-        match stage.ctrl() {
+        match ctrl {
             ControlType::Pressure => self.driver.set_target_pressure(sampled_output.into()),
             ControlType::Flow => self.driver.set_target_flow(sampled_output.into()),
             ControlType::Power => self.driver.set_target_power(sampled_output),
             ControlType::PistonPosition => self.driver.set_target_piston_position(sampled_output),
         }
+        self.setpoints.output = Some((ctrl, sampled_output));
 
         Ok(PS::Brewing)
     }
 
+    /// Blends from the previous stage's setpoint at the start of a stage and caps how fast the
+    /// setpoint may move, using the stage settings or else the profile wide ones
+    fn smooth_setpoint(&mut self, ctrl: ControlType, target: f64, stage_time: f64) -> f64 {
+        let stage = &self.profile.get_stages()[self.current_stage_id as usize];
+        let blend = stage
+            .transition_blend()
+            .or(self.profile.get_transition_blend());
+        let rate = stage
+            .ramp_rates()
+            .or(self.profile.get_ramp_rates())
+            .and_then(|r| r.get(ctrl));
+
+        let mut output = target;
+        if let (Some(duration), Some(from)) = (blend, self.blend_from) {
+            if stage_time < duration {
+                output = from + (target - from) * (stage_time / duration);
+            }
+        }
+
+        let now = self.clock.now();
+        if let (Some(rate), Some((last_ctrl, last, time))) = (rate, self.last_setpoint) {
+            if last_ctrl == ctrl {
+                let max_step = rate * now.saturating_sub(time).as_secs_f64();
+                output = output.clamp(last - max_step, last + max_step);
+            }
+        }
+
+        self.last_setpoint = Some((ctrl, output, now));
+        output
+    }
+
+    /// Sends the current stage's limits, falling back to the profile defaults, and clears any
+    /// limit left over from a previous stage so the machine default applies again
+    fn apply_limits(&mut self, inputs: &DynamicsInputs) -> [Option<f64>; LimitType::ALL.len()] {
+        let stage = &self.profile.get_stages()[self.current_stage_id as usize];
+        let values = LimitType::ALL.map(|kind| {
+            stage
+                .limits()
+                .iter()
+                .chain(self.profile.get_default_limits())
+                .find(|l| l.kind() == kind)
+                .map(|l| l.value(inputs))
+        });
+        self.send_limits(&values);
+        values
+    }
+
+    /// Sends the profile default limits outside of a stage, while retracting before the shot
+    fn apply_default_limits(&mut self) {
+        let values = LimitType::ALL.map(|kind| self.default_limit(kind));
+        self.send_limits(&values);
+    }
+
+    /// The profile default limit of `kind`, evaluated on the current sensor values
+    fn default_limit(&self, kind: LimitType) -> Option<f64> {
+        let sensors = self.driver.sensor_data();
+        let inputs = DynamicsInputs {
+            piston_position: sensors.piston_position(),
+            weight: sensors.weight(),
+            pressure: sensors.water_pressure(),
+            flow: sensors.water_flow(),
+            temperature: sensors.water_temp(),
+            ..Default::default()
+        };
+        self.profile
+            .get_default_limits()
+            .iter()
+            .find(|l| l.kind() == kind)
+            .map(|l| l.value(&inputs))
+    }
+
+    /// Sends the limits that are set and clears the active ones that are not
+    fn send_limits(&mut self, values: &[Option<f64>; LimitType::ALL.len()]) {
+        for (kind, value) in LimitType::ALL.into_iter().zip(values.iter().copied()) {
+            match value {
+                Some(value) => {
+                    match kind {
+                        LimitType::Pressure => self.driver.set_pressure_limit(Pressure::from(value)), // NOTE: In C++ it is limited flow?
+                        LimitType::Flow => self.driver.set_flow_limit(Flow::from(value)),
+                        LimitType::Power => self.driver.set_power_limit(value),
+                        LimitType::PistonSpeed => self.driver.set_piston_speed_limit(value),
+                        LimitType::Temperature => {
+                            self.driver.set_temperature_limit(Temp::from(value))
+                        }
+                    }
+                    self.active_limits |= 1u8 << kind as u8;
+                    self.setpoints.limits[kind as usize] = Some(value);
+                }
+                None if self.active_limits & (1u8 << kind as u8) != 0 => self.clear_limit(kind),
+                None => {}
+            }
+        }
+    }
+
+    /// Backs off power and piston position outputs when the measured pressure or flow exceeds
+    /// its limit, for hardware that cannot enforce the limits on its own
+    fn enforce_limits(
+        &mut self,
+        ctrl: ControlType,
+        output: f64,
+        limit_values: &[Option<f64>; LimitType::ALL.len()],
+    ) -> f64 {
+        let gain = match self.driver.limit_enforcement() {
+            LimitEnforcement::Software { gain }
+                if matches!(ctrl, ControlType::Power | ControlType::PistonPosition) =>
+            {
+                gain
+            }
+            _ => {
+                self.release_constraints(0);
+                return output;
+            }
+        };
+
+        let mut overshoot = 0.0f64;
+        let mut constraining = 0u8;
+        for (kind, measured) in [
+            (LimitType::Pressure, self.driver.sensor_data().water_pressure()),
+            (LimitType::Flow, self.driver.sensor_data().water_flow()),
+        ] {
+            let Some(limit) = limit_values[kind as usize] else {
+                continue;
+            };
+            if measured > limit {
+                if self.constraining & (1u8 << kind as u8) == 0 {
+                    self.emit(EngineEvent::LimitConstraining {
+                        kind,
+                        measured,
+                        limit,
+                    });
+                }
+                constraining |= 1u8 << kind as u8;
+                overshoot = overshoot.max((measured - limit) / limit.max(f64::EPSILON));
+            }
+        }
+        self.release_constraints(constraining);
+        if overshoot == 0.0 {
+            return output;
+        }
+
+        let scale = (1.0 - gain * overshoot).clamp(0.0, 1.0);
+        match ctrl {
+            ControlType::PistonPosition => {
+                let current = self.driver.sensor_data().piston_position();
+                current + (output - current) * scale
+            }
+            _ => output * scale,
+        }
+    }
+
+    fn clear_limit(&mut self, kind: LimitType) {
+        match kind {
+            LimitType::Pressure => self.driver.clear_pressure_limit(),
+            LimitType::Flow => self.driver.clear_flow_limit(),
+            LimitType::Power => self.driver.clear_power_limit(),
+            LimitType::PistonSpeed => self.driver.clear_piston_speed_limit(),
+            LimitType::Temperature => self.driver.clear_temperature_limit(),
+        }
+        self.active_limits &= !(1u8 << kind as u8);
+        self.setpoints.limits[kind as usize] = None;
+    }
+
+    /// Reports the end of every constraint that is not in the `still_constraining` bitmask
+    fn release_constraints(&mut self, still_constraining: u8) {
+        for kind in LimitType::ALL {
+            let bit = 1u8 << kind as u8;
+            if self.constraining & bit != 0 && still_constraining & bit == 0 {
+                self.emit(EngineEvent::LimitReleased { kind });
+            }
+        }
+        self.constraining = still_constraining;
+    }
+
+    fn clear_all_limits(&mut self) {
+        self.release_constraints(0);
+        for kind in LimitType::ALL {
+            if self.active_limits & (1u8 << kind as u8) != 0 {
+                self.clear_limit(kind);
+            }
+        }
+    }
+
+    fn update_dispensed_volume(&mut self, now: Duration) {
+        let dt = now.saturating_sub(self.last_step_time).as_secs_f64();
+        self.dispensed_volume += self.driver.sensor_data().water_flow() * dt;
+        self.last_step_time = now;
+    }
+
     pub fn get_state(&self) -> ProfileState {
         self.state
     }
 
-    fn transition_stage(&mut self, target_stage: u8) -> ProfileState {
+    /// Picks the value the new stage blends and ramps from. Across a control type change the
+    /// previous setpoint is meaningless, so the measured value of the new type is used instead
+    fn start_blend(&mut self) {
+        let ctrl = self.profile.get_stages()[self.current_stage_id as usize].ctrl();
+        self.blend_from = match self.last_setpoint {
+            Some((last_ctrl, last, _)) if last_ctrl == ctrl => Some(last),
+            _ => self.measured(ctrl),
+        };
+        self.last_setpoint = self
+            .blend_from
+            .map(|v| (ctrl, v, self.clock.now()));
+    }
+
+    /// The measured value of what `ctrl` commands, power is not measured
+    fn measured(&self, ctrl: ControlType) -> Option<f64> {
+        let sensors = self.driver.sensor_data();
+        match ctrl {
+            ControlType::Pressure => Some(sensors.water_pressure()),
+            ControlType::Flow => Some(sensors.water_flow()),
+            ControlType::PistonPosition => Some(sensors.piston_position()),
+            ControlType::Power => None,
+        }
+    }
+
+    fn transition_stage(&mut self, target_stage: u8, reason: StageExitReason) -> ProfileState {
         use ProfileState as PS;
 
-        self.save_stage_log(Some(self.stage_start_time));
+        self.save_stage_log(Some(reason));
 
         self.current_stage_id = target_stage;
 
@@ -236,11 +968,1144 @@ impl<'a, T: SensorState> ProfileEngineRunning<'a, T> {
             .profile
             .get_stages().len() > self.current_stage_id as usize
         {
-            self.stage_start_time = SystemTime::now();
+            // The entry is logged on the next step. A stage entered again, the current one
+            // included, keeps its previous visits
+            self.profile.get_stage_logs_mut()[self.current_stage_id as usize].start_visit();
+            self.stage_start_time = self.clock.now();
+            self.start_blend();
             PS::Brewing
         } else {
-            println!("Profile End reached via stage end");
+            self.emit(EngineEvent::ProfileEnded(EndReason::StageEnd));
             PS::Done
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::sensor::DummySensorState;
+    use json::{array, object, JsonValue};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    type Engine<'a> = ProfileEngineRunning<'a, DummySensorState, ManualClock>;
+    type Events = Rc<RefCell<Vec<EngineEvent>>>;
+
+    const TICK: Duration = Duration::from_millis(100);
+
+    fn stage(name: &str, seconds: u32) -> JsonValue {
+        controlled(name, "power", 50.0, seconds)
+    }
+
+    /// A stage holding `value` on `ctrl` for `seconds`
+    fn controlled(name: &str, ctrl: &str, value: f64, seconds: u32) -> JsonValue {
+        object! {
+            name: name,
+            type: ctrl,
+            dynamics: {
+                points: [[0, value]],
+                over: "time",
+                interpolation: "linear",
+            },
+            exit_triggers: [{ type: "time", value: seconds, relative: true }],
+        }
+    }
+
+    fn profile(stages: Vec<JsonValue>) -> Profile {
+        profile_with(stages, object! {})
+    }
+
+    /// `options` are added to the top level of the profile, like `auto_purge`
+    fn profile_with(stages: Vec<JsonValue>, options: JsonValue) -> Profile {
+        let mut value = object! {
+            name: "Test",
+            temperature: 93,
+            final_weight: 0,
+            stages: stages,
+        };
+        for (key, option) in options.entries() {
+            value[key] = option.clone();
+        }
+        Profile::try_from(&value).unwrap()
+    }
+
+    fn start<'a>(profile: &'a mut Profile, clock: &ManualClock) -> (Engine<'a>, Events) {
+        start_with(profile, clock, PurgeSettings::default())
+    }
+
+    fn start_with<'a>(
+        profile: &'a mut Profile,
+        clock: &ManualClock,
+        purge: PurgeSettings,
+    ) -> (Engine<'a>, Events) {
+        launch(profile, clock, Driver::default(), purge)
+    }
+
+    fn launch<'a>(
+        profile: &'a mut Profile,
+        clock: &ManualClock,
+        driver: Driver<DummySensorState>,
+        purge: PurgeSettings,
+    ) -> (Engine<'a>, Events) {
+        let events = Events::default();
+        let mut idle = ProfileEngineIdle::try_new(profile, driver)
+            .unwrap()
+            .with_clock(clock.clone())
+            .with_purge(purge);
+        let observed = events.clone();
+        idle.add_observer(move |e: &EngineEvent| observed.borrow_mut().push(*e));
+        (idle.start(), events)
+    }
+
+    fn step(engine: Engine) -> Engine {
+        match engine.step() {
+            Next(engine) => engine,
+            Finished(_) => panic!("engine finished"),
+            EngineStepResult::Error(e) => panic!("engine failed: {e}"),
+        }
+    }
+
+    /// Steps without moving the clock until the engine is in `state`
+    fn step_until(mut engine: Engine, state: ProfileState) -> Engine {
+        for _ in 0..10 {
+            if engine.get_state() == state {
+                return engine;
+            }
+            engine = step(engine);
+        }
+        panic!("engine is stuck in {:?}", engine.get_state());
+    }
+
+    /// Runs the shot in ticks until the last stage is done
+    fn brew<'a>(engine: Engine<'a>, clock: &ManualClock) -> Engine<'a> {
+        let mut engine = step_until(engine, ProfileState::Brewing);
+        for _ in 0..1000 {
+            if engine.get_state() != ProfileState::Brewing {
+                return engine;
+            }
+            clock.advance(TICK);
+            engine = step(engine);
+        }
+        panic!("the shot never ended");
+    }
+
+    fn move_piston(engine: &mut Engine, position: f64) {
+        *engine.driver.sensor_data_mut().piston_position = position;
+    }
+
+    fn changes(events: &Events) -> Vec<(ProfileState, ProfileState)> {
+        events
+            .borrow()
+            .iter()
+            .filter_map(|e| match e {
+                EngineEvent::StateChanged { from, to } => Some((*from, *to)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn finishes(engine: Engine) -> bool {
+        matches!(engine.step(), Finished(_))
+    }
+
+    fn entered(events: &Events) -> Vec<(u8, Duration)> {
+        events
+            .borrow()
+            .iter()
+            .filter_map(|e| match e {
+                EngineEvent::StageEntered { stage, time } => Some((*stage, *time)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn aborted_engine_starts_afresh() {
+        let clock = ManualClock::default();
+        let mut profile = profile(vec![stage("a", 5), stage("b", 5)]);
+        let events = Events::default();
+        let mut idle = ProfileEngineIdle::try_new(&mut profile, Driver::default())
+            .unwrap()
+            .with_clock(clock.clone())
+            .with_recording(64);
+        let observed = events.clone();
+        idle.add_observer(move |e: &EngineEvent| observed.borrow_mut().push(*e));
+
+        let mut engine = step(step_until(idle.start(), ProfileState::Brewing));
+        clock.advance(Duration::from_secs(1));
+        engine = step(engine);
+        let idle = engine.abort();
+        let log = &idle.profile.get_stage_logs()[0];
+        assert_eq!(log.get_exit_reason(), Some(StageExitReason::Aborted));
+
+        clock.advance(Duration::from_secs(10));
+        let restart = clock.now();
+        events.borrow_mut().clear();
+        let engine = step(step_until(idle.start(), ProfileState::Brewing));
+
+        let log = &engine.profile.get_stage_logs()[0];
+        assert_eq!(log.get_entry().unwrap().get_timestamp(), &restart);
+        assert!(log.get_exit().is_none());
+        assert_eq!(log.get_exit_reason(), None);
+        assert_eq!(entered(&events), [(0, restart)]);
+
+        let mut idle = engine.abort();
+        let recording = idle.take_recording().unwrap();
+        assert!(recording.samples().iter().all(|s| s.time >= restart));
+        assert_eq!(recording.samples().len(), 5);
+    }
+
+    #[test]
+    fn abort_before_the_stage_entry_is_logged() {
+        let clock = ManualClock::default();
+        let mut profile = profile(vec![stage("a", 1), stage("b", 5)]);
+        let (engine, events) = start(&mut profile, &clock);
+
+        let mut engine = step(step_until(engine, ProfileState::Brewing));
+        clock.advance(Duration::from_secs(1));
+        engine = step(engine);
+        // The trigger moved on to `b`, whose entry is only logged on the next tick
+        assert_eq!(engine.current_stage_id, 1);
+        assert!(!engine.profile.get_stage_logs()[1].is_valid());
+
+        let idle = engine.abort();
+        let log = &idle.profile.get_stage_logs()[1];
+        let now = clock.now();
+        assert_eq!(log.get_entry().unwrap().get_timestamp(), &now);
+        assert_eq!(log.get_exit().unwrap().get_timestamp(), &now);
+        assert_eq!(log.get_exit_reason(), Some(StageExitReason::Aborted));
+        assert!(events.borrow().contains(&EngineEvent::StageExited {
+            stage: 1,
+            time: now,
+            reason: StageExitReason::Aborted,
+        }));
+    }
+
+    #[test]
+    fn final_weight_exits_the_stage() {
+        let clock = ManualClock::default();
+        let options = object! { final_weight: 36 };
+        let mut profile = profile_with(vec![stage("a", 1), stage("b", 60)], options);
+        let (engine, events) = start(&mut profile, &clock);
+
+        let mut engine = step(step_until(engine, ProfileState::Brewing));
+        for _ in 0..20 {
+            clock.advance(TICK);
+            engine = step(engine);
+        }
+        engine.driver.sensor_data_mut().weight = 36.2;
+        clock.advance(TICK);
+        let engine = step(engine);
+        assert_eq!(engine.get_state(), ProfileState::Done);
+
+        let log = &engine.profile.get_stage_logs()[1];
+        assert_eq!(log.get_exit_reason(), Some(StageExitReason::FinalWeight));
+        assert_eq!(log.get_exit().unwrap().get_timestamp(), &clock.now());
+        let events = events.borrow();
+        let exited = events
+            .iter()
+            .position(|e| {
+                *e == EngineEvent::StageExited {
+                    stage: 1,
+                    time: clock.now(),
+                    reason: StageExitReason::FinalWeight,
+                }
+            })
+            .unwrap();
+        assert_eq!(
+            events[exited + 1],
+            EngineEvent::ProfileEnded(EndReason::FinalWeight)
+        );
+    }
+
+    fn output(engine: &Engine) -> f64 {
+        engine.setpoints.output.unwrap().1
+    }
+
+    fn assert_close(actual: f64, expected: f64, at: Duration) {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "{actual} instead of {expected} at {at:?}"
+        );
+    }
+
+    #[test]
+    fn ramp_rate_limits_the_first_stage() {
+        let clock = ManualClock::default();
+        let options = object! { ramp_rates: { pressure: 2 } };
+        let mut profile = profile_with(vec![controlled("a", "pressure", 9.0, 60)], options);
+        let (engine, _) = start(&mut profile, &clock);
+
+        // The first stage ramps from the measured 0 bar instead of jumping to 9 bar
+        let mut engine = step(step_until(engine, ProfileState::Brewing));
+        assert_close(output(&engine), 0.0, clock.now());
+        for tick in 1..=60 {
+            clock.advance(TICK);
+            engine = step(engine);
+            assert_close(output(&engine), (0.2 * tick as f64).min(9.0), clock.now());
+        }
+    }
+
+    #[test]
+    fn transition_blend_is_linear() {
+        let clock = ManualClock::default();
+        let stages = vec![
+            controlled("a", "pressure", 3.0, 1),
+            controlled("b", "pressure", 9.0, 60),
+        ];
+        let mut profile = profile_with(stages, object! { transition_blend: 2 });
+        let (engine, _) = start(&mut profile, &clock);
+
+        // Nothing to blend from in the first stage
+        let mut engine = step(step_until(engine, ProfileState::Brewing));
+        assert_close(output(&engine), 3.0, clock.now());
+        while engine.current_stage_id == 0 {
+            clock.advance(TICK);
+            engine = step(engine);
+        }
+        let stage_start = engine.stage_start_time;
+
+        for _ in 0..30 {
+            clock.advance(TICK);
+            engine = step(engine);
+            let stage_time = (clock.now() - stage_start).as_secs_f64();
+            let expected = if stage_time < 2.0 {
+                3.0 + 6.0 * stage_time / 2.0
+            } else {
+                9.0
+            };
+            assert_close(output(&engine), expected, clock.now());
+        }
+    }
+
+    #[test]
+    fn blend_across_control_types_starts_from_the_measured_value() {
+        let clock = ManualClock::default();
+        let stages = vec![
+            controlled("a", "flow", 2.0, 1),
+            controlled("b", "pressure", 9.0, 60),
+        ];
+        let mut profile = profile_with(stages, object! { transition_blend: 2 });
+        let (engine, _) = start(&mut profile, &clock);
+
+        let mut engine = step(step_until(engine, ProfileState::Brewing));
+        engine.driver.sensor_data_mut().water_pressure = 1.5;
+        while engine.current_stage_id == 0 {
+            clock.advance(TICK);
+            engine = step(engine);
+        }
+        for _ in 0..10 {
+            clock.advance(TICK);
+            engine = step(engine);
+        }
+        assert_eq!(engine.setpoints.output.unwrap().0, ControlType::Pressure);
+        assert_close(output(&engine), 1.5 + 7.5 * 0.5, clock.now());
+    }
+
+    #[test]
+    fn ramp_rate_applies_after_the_blend() {
+        let clock = ManualClock::default();
+        let stages = vec![
+            controlled("a", "pressure", 2.0, 1),
+            controlled("b", "pressure", 8.0, 60),
+        ];
+        let options = object! { transition_blend: 1, ramp_rates: { pressure: 3 } };
+        let mut profile = profile_with(stages, options);
+        let (engine, _) = start(&mut profile, &clock);
+
+        let mut engine = step(step_until(engine, ProfileState::Brewing));
+        // The first stage ramps to 2 bar at 3 bar/s
+        for _ in 0..10 {
+            clock.advance(TICK);
+            engine = step(engine);
+        }
+        assert_close(output(&engine), 2.0, clock.now());
+        while engine.current_stage_id == 0 {
+            clock.advance(TICK);
+            engine = step(engine);
+        }
+
+        // The blend would move 6 bar/s, the ramp rate holds it to 3 bar/s
+        for tick in 1..=30 {
+            clock.advance(TICK);
+            engine = step(engine);
+            assert_close(output(&engine), (2.0 + 0.3 * tick as f64).min(8.0), clock.now());
+        }
+    }
+
+    #[test]
+    fn software_control_backs_off_power_stages() {
+        let clock = ManualClock::default();
+        let mut limited = controlled("a", "power", 80.0, 60);
+        limited["limits"] = array![{ type: "pressure", value: 6 }];
+        let mut profile = profile(vec![limited]);
+        let pid = crate::control::PidConfig::new(5.0, 20.0, 0.0);
+        let driver = Driver::default().with_software_control(pid, pid);
+        let (engine, events) = launch(&mut profile, &clock, driver, PurgeSettings::default());
+
+        let mut engine = step(step_until(engine, ProfileState::Brewing));
+        assert_close(output(&engine), 80.0, clock.now());
+
+        // 50 % over the limit, the default gain of 0.5 takes a quarter of the power away
+        engine.driver.sensor_data_mut().water_pressure = 9.0;
+        clock.advance(TICK);
+        let engine = step(engine);
+        assert_close(output(&engine), 60.0, clock.now());
+        assert!(events.borrow().contains(&EngineEvent::LimitConstraining {
+            kind: LimitType::Pressure,
+            measured: 9.0,
+            limit: 6.0,
+        }));
+    }
+
+    #[test]
+    fn software_limits_back_off_until_released() {
+        let clock = ManualClock::default();
+        let mut limited = controlled("a", "power", 80.0, 60);
+        limited["limits"] = array![{ type: "pressure", value: 6 }];
+        let mut profile = profile(vec![limited]);
+        let driver = Driver::default().with_limit_enforcement(LimitEnforcement::DEFAULT_SOFTWARE);
+        let (engine, events) = launch(&mut profile, &clock, driver, PurgeSettings::default());
+        let constraints = |events: &Events| {
+            events
+                .borrow()
+                .iter()
+                .filter(|e| {
+                    matches!(
+                        e,
+                        EngineEvent::LimitConstraining { .. } | EngineEvent::LimitReleased { .. }
+                    )
+                })
+                .count()
+        };
+
+        let mut engine = step(step_until(engine, ProfileState::Brewing));
+        assert_close(output(&engine), 80.0, clock.now());
+
+        // Reported once however long the pressure stays over the limit
+        engine.driver.sensor_data_mut().water_pressure = 9.0;
+        for _ in 0..5 {
+            clock.advance(TICK);
+            engine = step(engine);
+            assert_close(output(&engine), 60.0, clock.now());
+        }
+        engine.driver.sensor_data_mut().water_pressure = 7.5;
+        clock.advance(TICK);
+        engine = step(engine);
+        assert_close(output(&engine), 70.0, clock.now());
+        assert_eq!(constraints(&events), 1);
+
+        engine.driver.sensor_data_mut().water_pressure = 5.0;
+        for _ in 0..3 {
+            clock.advance(TICK);
+            engine = step(engine);
+            assert_close(output(&engine), 80.0, clock.now());
+        }
+        assert_eq!(constraints(&events), 2);
+        assert!(events.borrow().contains(&EngineEvent::LimitReleased {
+            kind: LimitType::Pressure
+        }));
+    }
+
+    #[test]
+    fn setpoint_changes_only_when_the_value_does() {
+        let clock = ManualClock::default();
+        let mut ramp = controlled("b", "pressure", 0.0, 1);
+        ramp["dynamics"]["points"] = array![[1, 2], [1.5, 7], [10, 7]];
+        let mut profile = profile(vec![stage("a", 1), ramp]);
+        let (engine, events) = start(&mut profile, &clock);
+        let setpoints = |events: &Events| {
+            events
+                .borrow()
+                .iter()
+                .filter_map(|e| match e {
+                    EngineEvent::SetpointChanged { ctrl, value, .. } => Some((*ctrl, *value)),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let mut engine = step_until(engine, ProfileState::Brewing);
+        for _ in 0..9 {
+            clock.advance(TICK);
+            engine = step(engine);
+        }
+        assert_eq!(setpoints(&events), [(ControlType::Power, 50.0)]);
+
+        // The ramp over profile time changes every tick until it holds at 7 bar
+        for _ in 0..12 {
+            clock.advance(TICK);
+            engine = step(engine);
+        }
+        let changes = setpoints(&events);
+        assert_eq!(changes.len(), 6, "{changes:?}");
+        assert_eq!(changes.last(), Some(&(ControlType::Pressure, 7.0)));
+    }
+
+    /// A power stage that outputs its input, so the output shows what the dynamics saw
+    fn follows(name: &str, over: &str) -> JsonValue {
+        let mut stage = controlled(name, "power", 0.0, 60);
+        stage["dynamics"]["over"] = over.into();
+        stage["dynamics"]["points"] = array![[0, 0], [200, 200]];
+        stage
+    }
+
+    #[test]
+    fn dynamics_follow_their_input() {
+        for (over, expected) in [
+            ("time", 1.5),
+            ("stage_time", 0.5),
+            ("piston_position", 30.0),
+            ("weight", 12.5),
+            ("pressure", 3.25),
+            ("flow", 2.0),
+            ("temperature", 91.5),
+            ("volume", 3.0),
+        ] {
+            let clock = ManualClock::default();
+            let mut profile = profile(vec![stage("a", 1), follows("b", over)]);
+            let (engine, _) = start(&mut profile, &clock);
+            let mut engine = step_until(engine, ProfileState::Brewing);
+            let sensors = engine.driver.sensor_data_mut();
+            *sensors.piston_position = 30.0;
+            sensors.weight = 12.5;
+            sensors.water_pressure = 3.25;
+            sensors.water_flow = 2.0;
+            sensors.water_temp = 91.5;
+
+            // The second stage starts at 1 s
+            while clock.now() < Duration::from_millis(1500) {
+                clock.advance(TICK);
+                engine = step(engine);
+            }
+            assert_eq!(engine.current_stage_id, 1);
+            assert_close(output(&engine), expected, clock.now());
+        }
+    }
+
+    #[test]
+    fn volume_resets_when_brewing_starts() {
+        let clock = ManualClock::default();
+        let mut profile = profile(vec![follows("a", "volume")]);
+        let idle = ProfileEngineIdle::try_new(&mut profile, Driver::default())
+            .unwrap()
+            .with_clock(clock.clone());
+
+        // Water flowing while heating and retracting is not part of the shot
+        let mut engine = idle.start();
+        move_piston(&mut engine, 50.0);
+        engine.driver.sensor_data_mut().water_flow = 2.0;
+        engine = step_until(engine, ProfileState::Retracting);
+        for _ in 0..50 {
+            clock.advance(TICK);
+            engine = step(engine);
+        }
+        move_piston(&mut engine, 0.0);
+        engine = step(engine);
+        assert_eq!(engine.get_state(), ProfileState::Brewing);
+        for _ in 0..10 {
+            clock.advance(TICK);
+            engine = step(engine);
+        }
+        assert_close(output(&engine), 2.0, clock.now());
+
+        // Nor is the volume of an aborted shot
+        let idle = engine.abort();
+        clock.advance(Duration::from_secs(3));
+        let mut engine = step_until(idle.start(), ProfileState::Brewing);
+        engine.driver.sensor_data_mut().water_flow = 2.0;
+        for _ in 0..5 {
+            clock.advance(TICK);
+            engine = step(engine);
+        }
+        assert_close(output(&engine), 1.0, clock.now());
+    }
+
+    #[test]
+    fn relative_outputs_follow_the_entry_value() {
+        for (ctrl, relative, y, expected) in [
+            ("pressure", "absolute", 2.0, 2.0),
+            ("pressure", "offset", 2.0, 5.0),
+            ("pressure", "fraction", 0.5, 1.5),
+            ("flow", "offset", 1.0, 3.0),
+            ("flow", "fraction", 2.0, 4.0),
+        ] {
+            let clock = ManualClock::default();
+            let mut relative_stage = controlled("a", ctrl, y, 60);
+            relative_stage["dynamics"]["relative"] = relative.into();
+            let mut profile = profile(vec![relative_stage]);
+            let (engine, _) = start(&mut profile, &clock);
+            let mut engine = step_until(engine, ProfileState::Brewing);
+            engine.driver.sensor_data_mut().water_pressure = 3.0;
+            engine.driver.sensor_data_mut().water_flow = 2.0;
+
+            // The entry is logged on the first brewing tick
+            engine = step(engine);
+            assert_close(output(&engine), expected, clock.now());
+
+            // Later measurements do not move the reference
+            engine.driver.sensor_data_mut().water_pressure = 8.0;
+            engine.driver.sensor_data_mut().water_flow = 0.5;
+            clock.advance(TICK);
+            engine = step(engine);
+            assert_close(output(&engine), expected, clock.now());
+        }
+    }
+
+    #[test]
+    fn relative_output_is_taken_again_on_each_visit() {
+        let clock = ManualClock::default();
+        let mut hold = controlled("hold", "pressure", 1.0, 1);
+        hold["dynamics"]["relative"] = "offset".into();
+        let mut loops = stage("loop", 1);
+        loops["exit_triggers"][0]["target_stage"] = "hold".into();
+        let mut profile = profile(vec![hold, loops]);
+        let (engine, _) = start(&mut profile, &clock);
+        let mut engine = step(step_until(engine, ProfileState::Brewing));
+        engine.driver.sensor_data_mut().water_pressure = 2.0;
+        assert_close(output(&engine), 1.0, clock.now());
+
+        // Back in `hold` after `loop`, the measured 2 bar is the new reference
+        while engine.current_stage_id != 1 {
+            clock.advance(TICK);
+            engine = step(engine);
+        }
+        while engine.current_stage_id != 0 {
+            clock.advance(TICK);
+            engine = step(engine);
+        }
+        clock.advance(TICK);
+        engine = step(engine);
+        assert_close(output(&engine), 3.0, clock.now());
+    }
+
+    #[test]
+    fn dynamic_limits_are_evaluated_every_tick() {
+        let clock = ManualClock::default();
+        let mut tightening = controlled("a", "pressure", 9.0, 60);
+        tightening["limits"] = array![
+            {
+                type: "flow",
+                dynamics: {
+                    points: [[0, 6], [1, 3]],
+                    over: "stage_time",
+                    interpolation: "linear",
+                },
+            },
+            { type: "pressure", value: 10 },
+        ];
+        let mut profile = profile(vec![tightening]);
+        let (engine, _) = start(&mut profile, &clock);
+        let mut engine = step(step_until(engine, ProfileState::Brewing));
+
+        for tick in 0..=15 {
+            let expected = 6.0 - 3.0 * (tick as f64 / 10.0).min(1.0);
+            let [pressure, flow, ..] = limits(&engine);
+            assert_close(flow.unwrap(), expected, clock.now());
+            assert_eq!(pressure, Some(10.0));
+            clock.advance(TICK);
+            engine = step(engine);
+        }
+    }
+
+    #[test]
+    fn stage_temperatures() {
+        let clock = ManualClock::default();
+        let mut fixed = stage("fixed", 1);
+        fixed["temperature"] = 91.into();
+        let mut declining = stage("declining", 1);
+        declining["temperature"] = object! {
+            points: [[0, 92], [1, 88]],
+            over: "stage_time",
+            interpolation: "linear",
+        };
+        let mut profile = profile(vec![stage("start", 1), fixed, declining, stage("keep", 60)]);
+        let (engine, _) = start(&mut profile, &clock);
+        let temperature = |engine: &Engine| engine.setpoints.temperature.unwrap();
+
+        // The profile temperature until a stage sets its own
+        let mut engine = step(step_until(engine, ProfileState::Brewing));
+        assert_close(temperature(&engine), 93.0, clock.now());
+        while engine.current_stage_id != 2 {
+            clock.advance(TICK);
+            engine = step(engine);
+            if engine.current_stage_id == 1 && engine.profile.get_stage_logs()[1].is_valid() {
+                assert_close(temperature(&engine), 91.0, clock.now());
+            }
+        }
+
+        // Entered on the tick the previous stage ended, the curve is sampled from 0.1 s on
+        for tick in 1..10 {
+            clock.advance(TICK);
+            engine = step(engine);
+            assert_close(temperature(&engine), 92.0 - 0.4 * tick as f64, clock.now());
+        }
+
+        // A stage without a temperature keeps the last target
+        while engine.current_stage_id != 3 {
+            clock.advance(TICK);
+            engine = step(engine);
+        }
+        for _ in 0..5 {
+            clock.advance(TICK);
+            engine = step(engine);
+            assert_close(temperature(&engine), 88.4, clock.now());
+        }
+    }
+
+    #[test]
+    fn secondary_outputs_follow_their_curves() {
+        let clock = ManualClock::default();
+        let curve = |from: f64, to: f64| {
+            object! {
+                points: [[0, from], [1, to]],
+                over: "stage_time",
+                interpolation: "linear",
+            }
+        };
+        let mut multi = controlled("multi", "pressure", 0.0, 60);
+        multi["dynamics"] = curve(4.0, 9.0);
+        multi["outputs"] = array![
+            { type: "flow", dynamics: curve(6.0, 2.0) },
+            { type: "temperature", dynamics: curve(93.0, 88.0) },
+        ];
+        let mut profile = profile(vec![multi]);
+        let (engine, _) = start(&mut profile, &clock);
+        let mut engine = step(step_until(engine, ProfileState::Brewing));
+
+        // The main control, the flow ceiling and the temperature move together on every tick
+        for tick in 0..=12 {
+            let t = (tick as f64 / 10.0).min(1.0);
+            let now = clock.now();
+            assert_eq!(engine.setpoints.output.unwrap().0, ControlType::Pressure);
+            assert_close(output(&engine), 4.0 + 5.0 * t, now);
+            assert_close(limits(&engine)[LimitType::Flow as usize].unwrap(), 6.0 - 4.0 * t, now);
+            assert_close(engine.setpoints.temperature.unwrap(), 93.0 - 5.0 * t, now);
+            clock.advance(TICK);
+            engine = step(engine);
+        }
+    }
+
+    #[test]
+    fn done_without_auto_purge_stays_done() {
+        use ProfileState as PS;
+        let clock = ManualClock::default();
+        let mut profile = profile(vec![stage("a", 1), stage("b", 1)]);
+        let (engine, events) = start(&mut profile, &clock);
+
+        let mut engine = brew(engine, &clock);
+        assert_eq!(engine.get_state(), PS::Done);
+        for _ in 0..100 {
+            clock.advance(TICK);
+            engine = step(engine);
+        }
+        assert_eq!(engine.get_state(), PS::Done);
+        assert_eq!(
+            changes(&events),
+            [
+                (PS::Heating, PS::Ready),
+                (PS::Ready, PS::Retracting),
+                (PS::Retracting, PS::Brewing),
+                (PS::Brewing, PS::Done),
+            ]
+        );
+        assert!(events
+            .borrow()
+            .contains(&EngineEvent::ProfileEnded(EndReason::StageEnd)));
+    }
+
+    #[test]
+    fn auto_purge_finishes_once_the_piston_arrives() {
+        use ProfileState as PS;
+        let clock = ManualClock::default();
+        let mut profile = profile_with(vec![stage("a", 1)], object! { auto_purge: true });
+        let (engine, events) = start(&mut profile, &clock);
+
+        let mut engine = step(brew(engine, &clock));
+        assert_eq!(engine.get_state(), PS::Purging);
+        clock.advance(TICK);
+        engine = step(engine);
+        assert_eq!(engine.get_state(), PS::Purging);
+
+        move_piston(&mut engine, 99.5);
+        clock.advance(TICK);
+        assert!(finishes(engine));
+        assert_eq!(changes(&events).last(), Some(&(PS::Done, PS::Purging)));
+        assert_eq!(events.borrow().last(), Some(&EngineEvent::Finished));
+        assert!(!events
+            .borrow()
+            .iter()
+            .any(|e| matches!(e, EngineEvent::PurgeTimedOut { .. })));
+    }
+
+    #[test]
+    fn manual_purge_from_done() {
+        use ProfileState as PS;
+        let clock = ManualClock::default();
+        let mut profile = profile(vec![stage("a", 1)]);
+        let (engine, events) = start(&mut profile, &clock);
+
+        let mut engine = brew(engine, &clock);
+        engine.purge().unwrap();
+        assert_eq!(engine.get_state(), PS::Purging);
+        // Asking again while purging changes nothing
+        engine.purge().unwrap();
+        assert_eq!(changes(&events).last(), Some(&(PS::Done, PS::Purging)));
+
+        move_piston(&mut engine, 100.0);
+        clock.advance(TICK);
+        assert!(finishes(engine));
+        assert_eq!(events.borrow().last(), Some(&EngineEvent::Finished));
+    }
+
+    #[test]
+    fn purge_times_out() {
+        let clock = ManualClock::default();
+        let mut profile = profile(vec![stage("a", 1)]);
+        let purge = PurgeSettings::default().with_timeout(Duration::from_secs(2));
+        let (engine, events) = start_with(&mut profile, &clock, purge);
+
+        let mut engine = brew(engine, &clock);
+        let purge_start = clock.now();
+        engine.purge().unwrap();
+        // The piston is stuck half way
+        move_piston(&mut engine, 50.0);
+        for _ in 0..19 {
+            clock.advance(TICK);
+            engine = step(engine);
+        }
+        assert_eq!(engine.get_state(), ProfileState::Purging);
+
+        clock.advance(TICK);
+        assert!(finishes(engine));
+        let events = events.borrow();
+        assert_eq!(
+            events[events.len() - 2..],
+            [
+                EngineEvent::PurgeTimedOut {
+                    piston_position: 50.0,
+                    time: purge_start + Duration::from_secs(2),
+                },
+                EngineEvent::Finished,
+            ]
+        );
+    }
+
+    #[test]
+    fn purge_from_ready() {
+        use ProfileState as PS;
+        let clock = ManualClock::default();
+        let mut profile = profile_with(vec![stage("a", 1)], object! { wait_after_heating: true });
+        let (engine, events) = start(&mut profile, &clock);
+
+        let mut engine = step_until(engine, PS::Ready);
+        clock.advance(TICK);
+        engine = step(engine);
+        assert_eq!(engine.get_state(), PS::Ready);
+
+        engine.purge().unwrap();
+        assert_eq!(changes(&events).last(), Some(&(PS::Ready, PS::Purging)));
+        move_piston(&mut engine, 100.0);
+        clock.advance(TICK);
+        assert!(finishes(engine));
+    }
+
+    #[test]
+    fn purge_is_rejected_while_brewing() {
+        let clock = ManualClock::default();
+        let mut profile = profile(vec![stage("a", 5)]);
+        let (engine, events) = start(&mut profile, &clock);
+
+        let mut engine = step(step_until(engine, ProfileState::Brewing));
+        let before = events.borrow().len();
+        assert!(engine.purge().is_err());
+        assert_eq!(engine.get_state(), ProfileState::Brewing);
+        assert_eq!(engine.purge_start_time, None);
+        assert_eq!(events.borrow().len(), before);
+    }
+
+    fn limits(engine: &Engine) -> [Option<f64>; LimitType::ALL.len()] {
+        engine.setpoints.limits
+    }
+
+    #[test]
+    fn default_limits_apply_while_retracting() {
+        let clock = ManualClock::default();
+        let options = object! {
+            limits: [
+                { type: "piston_speed", value: 5 },
+                { type: "pressure", value: 9 },
+            ],
+        };
+        let mut profile = profile_with(vec![stage("a", 1)], options);
+        let (mut engine, _) = start(&mut profile, &clock);
+        move_piston(&mut engine, 40.0);
+
+        let mut engine = step(step_until(engine, ProfileState::Retracting));
+        assert_eq!(engine.get_state(), ProfileState::Retracting);
+        let expected = [Some(9.0), None, None, Some(5.0), None];
+        assert_eq!(limits(&engine), expected);
+
+        move_piston(&mut engine, 0.0);
+        let engine = step(step(engine));
+        assert_eq!(engine.get_state(), ProfileState::Brewing);
+        assert_eq!(limits(&engine), expected);
+
+        let engine = brew(engine, &clock);
+        assert_eq!(limits(&engine), [None; LimitType::ALL.len()]);
+    }
+
+    #[test]
+    fn stage_limits_are_cleared_on_stage_change() {
+        let clock = ManualClock::default();
+        let mut a = stage("a", 1);
+        a["limits"] = array![{ type: "flow", value: 3 }, { type: "pressure", value: 6 }];
+        let mut b = stage("b", 1);
+        b["limits"] = array![{ type: "piston_speed", value: 4 }];
+        let options = object! { limits: [{ type: "pressure", value: 9 }] };
+        let mut profile = profile_with(vec![a, b, stage("c", 60)], options);
+        let (engine, _) = start(&mut profile, &clock);
+        let flow_sent = |engine: &Engine| engine.active_limits & (1 << LimitType::Flow as u8) != 0;
+
+        let mut engine = step(step_until(engine, ProfileState::Brewing));
+        assert_eq!(limits(&engine), [Some(6.0), Some(3.0), None, None, None]);
+        assert!(flow_sent(&engine));
+
+        // The stage's own limits give way to the profile defaults or none at all
+        while engine.current_stage_id != 1 {
+            clock.advance(TICK);
+            engine = step(engine);
+        }
+        clock.advance(TICK);
+        engine = step(engine);
+        assert_eq!(limits(&engine), [Some(9.0), None, None, Some(4.0), None]);
+        assert!(!flow_sent(&engine));
+
+        // Manual changes too
+        engine.skip_stage().unwrap();
+        engine = step(engine);
+        assert_eq!(limits(&engine), [Some(9.0), None, None, None, None]);
+        engine.goto_stage("a").unwrap();
+        engine = step(engine);
+        assert_eq!(limits(&engine), [Some(6.0), Some(3.0), None, None, None]);
+    }
+
+    #[test]
+    fn purge_speed_falls_back_to_the_profile_default() {
+        let clock = ManualClock::default();
+        let options = object! {
+            limits: [
+                { type: "piston_speed", value: 5 },
+                { type: "flow", value: 4 },
+            ],
+        };
+        let speed = LimitType::PistonSpeed as usize;
+
+        let mut default_speed = profile_with(vec![stage("a", 1)], options.clone());
+        let (engine, _) = start(&mut default_speed, &clock);
+        let mut engine = brew(engine, &clock);
+        engine.purge().unwrap();
+        let mut expected = [None; LimitType::ALL.len()];
+        expected[speed] = Some(5.0);
+        assert_eq!(limits(&engine), expected);
+
+        let mut purge_speed = profile_with(vec![stage("a", 1)], options);
+        let purge = PurgeSettings::default().with_speed(2.0);
+        let (engine, _) = start_with(&mut purge_speed, &clock, purge);
+        let mut engine = brew(engine, &clock);
+        engine.purge().unwrap();
+        expected[speed] = Some(2.0);
+        assert_eq!(limits(&engine), expected);
+
+        let mut no_speed = profile(vec![stage("a", 1)]);
+        let (engine, _) = start(&mut no_speed, &clock);
+        let mut engine = brew(engine, &clock);
+        engine.purge().unwrap();
+        assert_eq!(limits(&engine), [None; LimitType::ALL.len()]);
+    }
+
+    /// Runs a three stage shot on a clock far from its epoch, returning the stage entries and
+    /// exits relative to the start of brewing
+    fn run_stages() -> (Vec<EngineEvent>, Vec<(Duration, Duration)>) {
+        let clock = ManualClock::default();
+        clock.advance(Duration::from_secs(1234));
+        let mut absolute = stage("c", 0);
+        absolute["exit_triggers"] = array![{ type: "time", value: 8 }];
+        let mut profile = profile(vec![stage("a", 2), stage("b", 3), absolute]);
+        let (engine, events) = start(&mut profile, &clock);
+
+        let start = clock.now();
+        let engine = brew(engine, &clock);
+        assert_eq!(engine.get_state(), ProfileState::Done);
+        drop(engine);
+
+        let stages = events
+            .borrow()
+            .iter()
+            .filter_map(|e| match *e {
+                EngineEvent::StageEntered { stage, time } => {
+                    Some(EngineEvent::StageEntered { stage, time: time - start })
+                }
+                EngineEvent::StageExited {
+                    stage,
+                    time,
+                    reason,
+                } => Some(EngineEvent::StageExited {
+                    stage,
+                    time: time - start,
+                    reason,
+                }),
+                _ => None,
+            })
+            .collect();
+        let logs = profile
+            .get_stage_logs()
+            .iter()
+            .map(|log| {
+                (
+                    *log.get_entry().unwrap().get_timestamp() - start,
+                    *log.get_exit().unwrap().get_timestamp() - start,
+                )
+            })
+            .collect();
+        (stages, logs)
+    }
+
+    #[test]
+    fn stage_transition_times() {
+        let ms = Duration::from_millis;
+        let entered = |stage, time| EngineEvent::StageEntered {
+            stage,
+            time: ms(time),
+        };
+        let exited = |stage, time| EngineEvent::StageExited {
+            stage,
+            time: ms(time),
+            reason: StageExitReason::Trigger,
+        };
+
+        let (stages, logs) = run_stages();
+        // A stage's timer starts when brewing starts or the previous stage exits, its entry is
+        // logged on the next tick. The last stage exits on the shot time rather than its own
+        assert_eq!(
+            stages,
+            [
+                entered(0, 100),
+                exited(0, 2000),
+                entered(1, 2100),
+                exited(1, 5000),
+                entered(2, 5100),
+                exited(2, 8000),
+            ]
+        );
+        assert_eq!(
+            logs,
+            [
+                (ms(100), ms(2000)),
+                (ms(2100), ms(5000)),
+                (ms(5100), ms(8000)),
+            ]
+        );
+
+        // Nothing depends on the wall clock, another run is identical
+        assert_eq!(run_stages(), (stages, logs));
+    }
+
+    fn visits(log: &StageLog) -> Vec<(u64, Option<u64>, Option<StageExitReason>)> {
+        let ms = |vars: &StageVariables| vars.get_timestamp().as_millis() as u64;
+        log.earlier_visits()
+            .iter()
+            .map(|v| (ms(v.get_entry()), v.get_exit().map(ms), v.get_exit_reason()))
+            .chain(
+                log.get_entry()
+                    .map(|entry| (ms(entry), log.get_exit().map(ms), log.get_exit_reason())),
+            )
+            .collect()
+    }
+
+    #[test]
+    fn trigger_loops_keep_every_visit() {
+        use StageExitReason::{Aborted, Trigger};
+        let clock = ManualClock::default();
+        let mut back = stage("b", 1);
+        back["exit_triggers"][0]["target_stage"] = "a".into();
+        let mut profile = profile(vec![stage("a", 1), back]);
+        let (engine, _) = start(&mut profile, &clock);
+
+        let mut engine = step(step_until(engine, ProfileState::Brewing));
+        for _ in 0..45 {
+            clock.advance(TICK);
+            engine = step(engine);
+        }
+        let idle = engine.abort();
+
+        // Each visit is entered on the tick after the previous stage exits
+        let logs = idle.profile.get_stage_logs();
+        assert_eq!(
+            visits(&logs[0]),
+            [
+                (0, Some(1000), Some(Trigger)),
+                (2100, Some(3000), Some(Trigger)),
+                (4100, Some(4500), Some(Aborted)),
+            ]
+        );
+        assert_eq!(
+            visits(&logs[1]),
+            [(1100, Some(2000), Some(Trigger)), (3100, Some(4000), Some(Trigger))]
+        );
+    }
+
+    #[test]
+    fn stage_changes_are_rejected_while_paused() {
+        let clock = ManualClock::default();
+        let mut profile = profile(vec![stage("a", 5), stage("b", 5)]);
+        let (engine, _) = start(&mut profile, &clock);
+        let mut engine = step(step_until(engine, ProfileState::Brewing));
+
+        clock.advance(Duration::from_secs(1));
+        engine.pause();
+        assert!(engine.skip_stage().is_err());
+        assert!(engine.goto_stage("b").is_err());
+        assert!(engine.purge().is_err());
+
+        clock.advance(Duration::from_secs(3));
+        engine.resume();
+        engine.skip_stage().unwrap();
+        let engine = step(engine);
+        assert_eq!(engine.current_stage_id, 1);
+        assert_eq!(engine.stage_start_time, clock.now());
+        // The three paused seconds do not count towards the shot
+        assert_eq!(engine.profile_start_time, Duration::from_secs(3));
+    }
+
+    #[test]
+    fn goto_current_stage_keeps_exit_reason() {
+        let clock = ManualClock::default();
+        let mut profile = profile(vec![stage("a", 5), stage("b", 5)]);
+        let (engine, events) = start(&mut profile, &clock);
+        let mut engine = step(step_until(engine, ProfileState::Brewing));
+
+        clock.advance(Duration::from_secs(2));
+        engine.goto_stage(0).unwrap();
+        clock.advance(TICK);
+        let engine = step(engine);
+
+        // The jump ended the first visit, the latest one has only just been entered
+        let log = &engine.profile.get_stage_logs()[0];
+        assert_eq!(log.get_entry().unwrap().get_timestamp(), &clock.now());
+        assert!(log.get_exit().is_none());
+        assert_eq!(log.get_exit_reason(), None);
+        assert_eq!(engine.stage_start_time, Duration::from_secs(2));
+
+        let [first] = log.earlier_visits() else {
+            panic!("{:?}", log.earlier_visits());
+        };
+        assert_eq!(first.get_entry().get_timestamp(), &Duration::ZERO);
+        assert_eq!(first.get_exit().unwrap().get_timestamp(), &Duration::from_secs(2));
+        assert_eq!(first.get_exit_reason(), Some(StageExitReason::Jumped));
+
+        assert_eq!(entered(&events), [(0, Duration::ZERO), (0, clock.now())]);
+    }
+}
@@ -0,0 +1,493 @@
+//! Shot exports for offline analysis.
+//!
+//! The JSON shot format (`"format": "micro-profile-engine-shot"`, `"version": 1`) is one object:
+//!
+//! - `profile`: `{ "id", "name" }` of the profile, each `null` when the profile has none
+//! - `stages`: per stage `{ "index", "name", "entry", "exit", "exit_reason", "earlier_visits" }`
+//!   for its latest visit, where `entry` and `exit` are `{ "time", "pressure", "flow",
+//!   "piston_position" }` snapshots or `null` if never reached, and `exit_reason` is
+//!   `"trigger"`, `"aborted"`, `"skipped"`, `"jumped"` or `"final_weight"`. A stage entered more
+//!   than once lists its previous visits as `{ "entry", "exit", "exit_reason" }` in
+//!   `earlier_visits`, oldest first
+//! - `samples`: per engine tick `{ "time", "state", "stage", <reading>..., "has_water", "input",
+//!   "output", "setpoint": { "type", "value" }, "temperature_target", "limits": { <type>: value }
+//!   }`, empty when the shot was not recorded. The readings are every sensor of the machine and
+//!   the dispensed volume, named as in `SensorSample::NAMES`: "pressure", "flow", "weight",
+//!   "water_temp", "piston_position", "piston_speed", "volume" and the auxiliary temperatures
+//! - `dropped_samples`: ticks that did not fit in the recording buffer
+//!
+//! Times are seconds on the engine clock, pressures in bar, flows in ml/s, weights in g,
+//! temperatures in °C and positions in %. Values that were not available are `null`.
+//! The CSV exports use the same names as column headers and leave unavailable values empty.
+//! The stage log CSV has a row per entry and exit, `visit` counts the visits of a stage from 0.
+
+pub mod visualizer;
+
+use crate::profile::dynamics::LimitType;
+use crate::profile::{Profile, StageVariables};
+use crate::recorder::{SensorSample, ShotRecording, ShotSample};
+use json::{object, JsonValue};
+use std::io::{self, Write};
+
+pub fn write_stage_logs_csv<W: Write>(w: &mut W, profile: &Profile) -> io::Result<()> {
+    writeln!(
+        w,
+        "index,name,visit,event,time,pressure,flow,piston_position,exit_reason"
+    )?;
+    for (i, (stage, log)) in profile
+        .get_stages()
+        .iter()
+        .zip(profile.get_stage_logs())
+        .enumerate()
+    {
+        let visits = log
+            .earlier_visits()
+            .iter()
+            .map(|v| (Some(v.get_entry()), v.get_exit(), v.get_exit_reason()))
+            .chain([(log.get_entry(), log.get_exit(), log.get_exit_reason())]);
+        for (visit, (entry, exit, exit_reason)) in visits.enumerate() {
+            let exit_reason = exit_reason.map(|r| r.name()).unwrap_or("");
+            for (event, vars, reason) in [("entry", entry, ""), ("exit", exit, exit_reason)] {
+                let Some(vars) = vars else {
+                    continue;
+                };
+                writeln!(
+                    w,
+                    "{i},{},{visit},{event},{},{},{},{},{reason}",
+                    csv_escape(stage.name()),
+                    vars.get_timestamp().as_secs_f64(),
+                    f64::from(vars.get_pressure()),
+                    f64::from(vars.get_flow()),
+                    f64::from(vars.get_piston_pos()),
+                )?;
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn write_recording_csv<W: Write>(w: &mut W, recording: &ShotRecording) -> io::Result<()> {
+    write!(w, "time,state,stage")?;
+    for name in SensorSample::NAMES {
+        write!(w, ",{name}")?;
+    }
+    write!(
+        w,
+        ",has_water,input,output,setpoint_type,setpoint,temperature_target"
+    )?;
+    for kind in LimitType::ALL {
+        write!(w, ",limit_{}", kind.name())?;
+    }
+    writeln!(w)?;
+
+    for s in recording.samples() {
+        write!(w, "{},{:?},{}", s.time.as_secs_f64(), s.state, s.stage)?;
+        for value in s.sensors.values() {
+            write!(w, ",{value}")?;
+        }
+        write!(
+            w,
+            ",{},{},{},{},{},{}",
+            s.sensors.has_water,
+            csv_opt(s.input),
+            csv_opt(s.output),
+            s.setpoints.output.map(|(c, _)| c.name()).unwrap_or(""),
+            csv_opt(s.setpoints.output.map(|(_, v)| v)),
+            csv_opt(s.setpoints.temperature),
+        )?;
+        for limit in s.setpoints.limits {
+            write!(w, ",{}", csv_opt(limit))?;
+        }
+        writeln!(w)?;
+    }
+    Ok(())
+}
+
+pub fn shot_to_json(profile: &Profile, recording: Option<&ShotRecording>) -> JsonValue {
+    let stages: Vec<JsonValue> = profile
+        .get_stages()
+        .iter()
+        .zip(profile.get_stage_logs())
+        .enumerate()
+        .map(|(i, (stage, log))| {
+            let earlier_visits: Vec<JsonValue> = log
+                .earlier_visits()
+                .iter()
+                .map(|v| {
+                    object! {
+                        entry: stage_variables_to_json(v.get_entry()),
+                        exit: v.get_exit().map(stage_variables_to_json),
+                        exit_reason: v.get_exit_reason().map(|r| r.name()),
+                    }
+                })
+                .collect();
+            object! {
+                index: i,
+                name: stage.name(),
+                entry: log.get_entry().map(stage_variables_to_json),
+                exit: log.get_exit().map(stage_variables_to_json),
+                exit_reason: log.get_exit_reason().map(|r| r.name()),
+                earlier_visits: earlier_visits,
+            }
+        })
+        .collect();
+    let samples: Vec<JsonValue> = recording
+        .map(|r| r.samples().iter().map(sample_to_json).collect())
+        .unwrap_or_default();
+
+    object! {
+        format: "micro-profile-engine-shot",
+        version: 1,
+        profile: {
+            id: profile.get_id(),
+            name: profile.get_name(),
+        },
+        stages: stages,
+        samples: samples,
+        dropped_samples: recording.map(|r| r.dropped()).unwrap_or(0),
+    }
+}
+
+pub fn write_shot_json<W: Write>(
+    w: &mut W,
+    profile: &Profile,
+    recording: Option<&ShotRecording>,
+) -> io::Result<()> {
+    shot_to_json(profile, recording).write_pretty(w, 2)
+}
+
+fn stage_variables_to_json(vars: &StageVariables) -> JsonValue {
+    object! {
+        time: vars.get_timestamp().as_secs_f64(),
+        pressure: f64::from(vars.get_pressure()),
+        flow: f64::from(vars.get_flow()),
+        piston_position: f64::from(vars.get_piston_pos()),
+    }
+}
+
+fn sample_to_json(s: &ShotSample) -> JsonValue {
+    let mut limits = JsonValue::new_object();
+    for (kind, limit) in LimitType::ALL.into_iter().zip(s.setpoints.limits) {
+        if let Some(limit) = limit {
+            limits[kind.name()] = limit.into();
+        }
+    }
+
+    let mut sample = object! {
+        time: s.time.as_secs_f64(),
+        state: format!("{:?}", s.state),
+        stage: s.stage,
+    };
+    for (name, value) in SensorSample::NAMES.into_iter().zip(s.sensors.values()) {
+        sample[name] = value.into();
+    }
+    sample["has_water"] = s.sensors.has_water.into();
+    sample["input"] = s.input.into();
+    sample["output"] = s.output.into();
+    sample["setpoint"] = s
+        .setpoints
+        .output
+        .map(|(ctrl, value)| object! { type: ctrl.name(), value: value })
+        .into();
+    sample["temperature_target"] = s.setpoints.temperature.into();
+    sample["limits"] = limits;
+    sample
+}
+
+fn csv_opt(v: Option<f64>) -> String {
+    v.map(|v| v.to_string()).unwrap_or_default()
+}
+
+fn csv_escape(s: &str) -> String {
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::ProfileState;
+    use crate::profile::dynamics::ControlType;
+    use crate::profile::StageExitReason;
+    use crate::recorder::Setpoints;
+    use json::array;
+    use std::time::Duration;
+
+    fn vars(time: f64, pressure: f64, flow: f64, piston_position: f64) -> StageVariables {
+        StageVariables::new(
+            flow.into(),
+            pressure.into(),
+            piston_position.into(),
+            Duration::from_secs_f64(time),
+        )
+    }
+
+    /// Bloom, a jump back to it from the second stage, a skip and a final weight end
+    pub(super) fn shot_profile() -> Profile {
+        let stage = |name: &str| {
+            object! {
+                name: name,
+                type: "pressure",
+                dynamics: { points: [[0, 6]], over: "time", interpolation: "linear" },
+                exit_triggers: [{ type: "time", value: 10, relative: true }],
+            }
+        };
+        let mut profile = Profile::try_from(&object! {
+            id: "p-1",
+            name: "Golden shot",
+            temperature: 93,
+            final_weight: 36,
+            stages: [stage("Bloom"), stage("Hold, then taper"), stage("Unused")],
+        })
+        .unwrap();
+
+        let logs = profile.get_stage_logs_mut();
+        logs[0].put_entry_log(vars(0.0, 0.5, 1.0, 0.0));
+        logs[0].put_exit_log(vars(1.5, 2.0, 3.0, 10.0));
+        logs[0].put_exit_reason(StageExitReason::Trigger);
+        logs[1].put_entry_log(vars(1.5, 2.0, 3.0, 10.0));
+        logs[1].put_exit_log(vars(4.0, 6.0, 2.0, 40.0));
+        logs[1].put_exit_reason(StageExitReason::Jumped);
+        logs[0].start_visit();
+        logs[0].put_entry_log(vars(4.0, 6.0, 2.0, 40.0));
+        logs[0].put_exit_log(vars(5.0, 6.5, 1.5, 45.0));
+        logs[0].put_exit_reason(StageExitReason::Skipped);
+        logs[1].start_visit();
+        logs[1].put_entry_log(vars(5.0, 6.5, 1.5, 45.0));
+        logs[1].put_exit_log(vars(7.25, 1.0, 0.5, 80.0));
+        logs[1].put_exit_reason(StageExitReason::FinalWeight);
+        profile
+    }
+
+    /// Retracting, two brewing ticks on different control types and a dropped tick once done
+    pub(super) fn recording() -> ShotRecording {
+        let mut recording = ShotRecording::with_capacity(4);
+        let done = ShotSample {
+            time: Duration::from_millis(300),
+            state: ProfileState::Done,
+            stage: 1,
+            sensors: SensorSample {
+                weight: 0.5,
+                water_temp: 93.0,
+                piston_position: 2.0,
+                volume: 0.3,
+                cylinder_temperature: 90.0,
+                motor_encoder: 13.0,
+                has_water: true,
+                ..Default::default()
+            },
+            input: None,
+            output: None,
+            setpoints: Setpoints::default(),
+        };
+        recording.push(ShotSample {
+            time: Duration::ZERO,
+            state: ProfileState::Retracting,
+            stage: 0,
+            sensors: SensorSample {
+                water_temp: 92.0,
+                piston_position: 3.0,
+                has_water: true,
+                ..Default::default()
+            },
+            input: None,
+            output: None,
+            setpoints: Setpoints {
+                output: Some((ControlType::PistonPosition, 0.0)),
+                temperature: Some(93.0),
+                limits: [Some(9.0), None, None, Some(5.0), None],
+            },
+        });
+        recording.push(ShotSample {
+            time: Duration::from_millis(100),
+            state: ProfileState::Brewing,
+            stage: 0,
+            sensors: SensorSample {
+                pressure: 0.5,
+                flow: 1.0,
+                weight: 0.0,
+                water_temp: 92.5,
+                piston_position: 1.0,
+                piston_speed: 2.0,
+                volume: 0.1,
+                cylinder_temperature: 90.0,
+                motor_encoder: 12.0,
+                ..done.sensors
+            },
+            input: Some(0.1),
+            output: Some(2.0),
+            setpoints: Setpoints {
+                output: Some((ControlType::Pressure, 2.0)),
+                temperature: Some(93.0),
+                limits: [None, Some(4.0), None, None, None],
+            },
+        });
+        recording.push(ShotSample {
+            time: Duration::from_millis(200),
+            state: ProfileState::Brewing,
+            stage: 1,
+            sensors: SensorSample {
+                pressure: 1.25,
+                flow: 2.0,
+                piston_speed: 2.0,
+                ..done.sensors
+            },
+            input: Some(0.2),
+            output: Some(2.5),
+            setpoints: Setpoints {
+                output: Some((ControlType::Flow, 2.5)),
+                temperature: Some(93.0),
+                limits: [None; LimitType::ALL.len()],
+            },
+        });
+        recording.push(done);
+        recording.push(done);
+        recording
+    }
+
+    fn write(f: impl FnOnce(&mut Vec<u8>) -> io::Result<()>) -> String {
+        let mut out = vec![];
+        f(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn stage_logs_csv() {
+        let profile = shot_profile();
+        assert_eq!(
+            write(|w| write_stage_logs_csv(w, &profile)),
+            "index,name,visit,event,time,pressure,flow,piston_position,exit_reason\n\
+             0,Bloom,0,entry,0,0.5,1,0,\n\
+             0,Bloom,0,exit,1.5,2,3,10,trigger\n\
+             0,Bloom,1,entry,4,6,2,40,\n\
+             0,Bloom,1,exit,5,6.5,1.5,45,skipped\n\
+             1,\"Hold, then taper\",0,entry,1.5,2,3,10,\n\
+             1,\"Hold, then taper\",0,exit,4,6,2,40,jumped\n\
+             1,\"Hold, then taper\",1,entry,5,6.5,1.5,45,\n\
+             1,\"Hold, then taper\",1,exit,7.25,1,0.5,80,final_weight\n"
+        );
+    }
+
+    #[test]
+    fn recording_csv() {
+        let recording = recording();
+        assert_eq!(
+            write(|w| write_recording_csv(w, &recording)),
+            "time,state,stage,pressure,flow,weight,water_temp,piston_position,piston_speed,volume,\
+             cylinder_temperature,external_temperature_1,external_temperature_2,tube_temperature,\
+             plunger_temperature,predictive_temperature,stable_temperature,temperature_up,\
+             temperature_middle_up,temperature_middle_down,temperature_down,output_position,\
+             motor_encoder,has_water,input,output,setpoint_type,setpoint,temperature_target,\
+             limit_pressure,limit_flow,limit_power,limit_piston_speed,limit_temperature\n\
+             0,Retracting,0,0,0,0,92,3,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,true,\
+             ,,piston_position,0,93,9,,,5,\n\
+             0.1,Brewing,0,0.5,1,0,92.5,1,2,0.1,90,0,0,0,0,0,0,0,0,0,0,0,12,true,\
+             0.1,2,pressure,2,93,,4,,,\n\
+             0.2,Brewing,1,1.25,2,0.5,93,2,2,0.3,90,0,0,0,0,0,0,0,0,0,0,0,13,true,\
+             0.2,2.5,flow,2.5,93,,,,,\n\
+             0.3,Done,1,0,0,0.5,93,2,0,0.3,90,0,0,0,0,0,0,0,0,0,0,0,13,true,\
+             ,,,,,,,,,\n"
+        );
+    }
+
+    #[test]
+    fn shot_json() {
+        let profile = shot_profile();
+        let recording = recording();
+        let shot = shot_to_json(&profile, Some(&recording));
+
+        let vars = |time: f64, pressure: f64, flow: f64, piston_position: f64| {
+            object! { time: time, pressure: pressure, flow: flow, piston_position: piston_position }
+        };
+        assert_eq!(shot["format"], "micro-profile-engine-shot");
+        assert_eq!(shot["version"], 1);
+        assert_eq!(shot["profile"], object! { id: "p-1", name: "Golden shot" });
+        assert_eq!(
+            shot["stages"],
+            array![
+                {
+                    index: 0,
+                    name: "Bloom",
+                    entry: vars(4.0, 6.0, 2.0, 40.0),
+                    exit: vars(5.0, 6.5, 1.5, 45.0),
+                    exit_reason: "skipped",
+                    earlier_visits: [{
+                        entry: vars(0.0, 0.5, 1.0, 0.0),
+                        exit: vars(1.5, 2.0, 3.0, 10.0),
+                        exit_reason: "trigger",
+                    }],
+                },
+                {
+                    index: 1,
+                    name: "Hold, then taper",
+                    entry: vars(5.0, 6.5, 1.5, 45.0),
+                    exit: vars(7.25, 1.0, 0.5, 80.0),
+                    exit_reason: "final_weight",
+                    earlier_visits: [{
+                        entry: vars(1.5, 2.0, 3.0, 10.0),
+                        exit: vars(4.0, 6.0, 2.0, 40.0),
+                        exit_reason: "jumped",
+                    }],
+                },
+                {
+                    index: 2,
+                    name: "Unused",
+                    entry: null,
+                    exit: null,
+                    exit_reason: null,
+                    earlier_visits: [],
+                },
+            ]
+        );
+        assert_eq!(shot["dropped_samples"], 1);
+
+        assert_eq!(shot["samples"].len(), 4);
+        assert_eq!(
+            shot["samples"][0]["setpoint"],
+            object! { type: "piston_position", value: 0.0 }
+        );
+        assert_eq!(
+            shot["samples"][0]["limits"],
+            object! { pressure: 9.0, piston_speed: 5.0 }
+        );
+        assert_eq!(
+            shot["samples"][2],
+            object! {
+                time: 0.2,
+                state: "Brewing",
+                stage: 1,
+                pressure: 1.25,
+                flow: 2.0,
+                weight: 0.5,
+                water_temp: 93.0,
+                piston_position: 2.0,
+                piston_speed: 2.0,
+                volume: 0.3,
+                cylinder_temperature: 90.0,
+                external_temperature_1: 0.0,
+                external_temperature_2: 0.0,
+                tube_temperature: 0.0,
+                plunger_temperature: 0.0,
+                predictive_temperature: 0.0,
+                stable_temperature: 0.0,
+                temperature_up: 0.0,
+                temperature_middle_up: 0.0,
+                temperature_middle_down: 0.0,
+                temperature_down: 0.0,
+                output_position: 0.0,
+                motor_encoder: 13.0,
+                has_water: true,
+                input: 0.2,
+                output: 2.5,
+                setpoint: { type: "flow", value: 2.5 },
+                temperature_target: 93.0,
+                limits: {},
+            }
+        );
+        assert!(shot["samples"][3]["setpoint"].is_null());
+        assert!(shot["samples"][3]["input"].is_null());
+    }
+}
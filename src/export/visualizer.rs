@@ -0,0 +1,178 @@
+//! Decent Espresso `.shot` files, as accepted by Visualizer.coffee uploads.
+//!
+//! The file is a flat list of Tcl style `key {values}` lines. Only the brewing part of the
+//! recording is written, with `espresso_elapsed` starting at 0. Goals that the stage did not
+//! control are written as `-1`, like the DE1 app does.
+
+use crate::engine::ProfileState;
+use crate::profile::dynamics::ControlType;
+use crate::profile::Profile;
+use crate::recorder::{ShotRecording, ShotSample};
+use std::io::{self, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub fn write_decent_shot<W: Write>(
+    w: &mut W,
+    profile: &Profile,
+    recording: &ShotRecording,
+    shot_start: SystemTime,
+) -> io::Result<()> {
+    let samples: Vec<&ShotSample> = recording
+        .samples()
+        .iter()
+        .filter(|s| s.state == ProfileState::Brewing)
+        .collect();
+    let start = samples.first().map(|s| s.time).unwrap_or_default();
+    let elapsed: Vec<f64> = samples
+        .iter()
+        .map(|s| s.time.saturating_sub(start).as_secs_f64())
+        .collect();
+
+    let clock = shot_start
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    writeln!(w, "clock {clock}")?;
+
+    write_series(w, "espresso_elapsed", elapsed.iter().copied())?;
+    write_series(
+        w,
+        "espresso_pressure",
+        samples.iter().map(|s| s.sensors.pressure),
+    )?;
+    write_series(w, "espresso_flow", samples.iter().map(|s| s.sensors.flow))?;
+    write_series(
+        w,
+        "espresso_weight",
+        samples.iter().map(|s| s.sensors.weight),
+    )?;
+    write_series(
+        w,
+        "espresso_flow_weight",
+        (0..samples.len()).map(|i| {
+            if i == 0 || elapsed[i] <= elapsed[i - 1] {
+                0.0
+            } else {
+                (samples[i].sensors.weight - samples[i - 1].sensors.weight)
+                    / (elapsed[i] - elapsed[i - 1])
+            }
+        }),
+    )?;
+    write_series(
+        w,
+        "espresso_temperature_basket",
+        samples.iter().map(|s| s.sensors.water_temp),
+    )?;
+    write_series(
+        w,
+        "espresso_temperature_mix",
+        samples.iter().map(|s| s.sensors.water_temp),
+    )?;
+    write_series(
+        w,
+        "espresso_pressure_goal",
+        samples.iter().map(|s| goal(s, ControlType::Pressure)),
+    )?;
+    write_series(
+        w,
+        "espresso_flow_goal",
+        samples.iter().map(|s| goal(s, ControlType::Flow)),
+    )?;
+    write_series(
+        w,
+        "espresso_temperature_goal",
+        samples
+            .iter()
+            .map(|s| s.setpoints.temperature.unwrap_or(-1.0)),
+    )?;
+
+    writeln!(w, "settings {{")?;
+    writeln!(
+        w,
+        "\tprofile_title {}",
+        tcl_word(profile.get_name().unwrap_or("Untitled"))
+    )?;
+    writeln!(
+        w,
+        "\tespresso_temperature {}",
+        f64::from(profile.get_starting_temp())
+    )?;
+    writeln!(
+        w,
+        "\tfinal_desired_shot_weight {}",
+        f64::from(profile.get_target_weight())
+    )?;
+    writeln!(
+        w,
+        "\tdrink_weight {}",
+        samples.last().map(|s| s.sensors.weight).unwrap_or(0.0)
+    )?;
+    writeln!(w, "}}")
+}
+
+fn goal(sample: &ShotSample, ctrl: ControlType) -> f64 {
+    match sample.setpoints.output {
+        Some((c, v)) if c == ctrl => v,
+        _ => -1.0,
+    }
+}
+
+fn write_series<W: Write>(
+    w: &mut W,
+    name: &str,
+    values: impl Iterator<Item = f64>,
+) -> io::Result<()> {
+    write!(w, "{name} {{")?;
+    for (i, v) in values.enumerate() {
+        if i > 0 {
+            write!(w, " ")?;
+        }
+        write!(w, "{v:.2}")?;
+    }
+    writeln!(w, "}}")
+}
+
+fn tcl_word(s: &str) -> String {
+    let s: String = s.chars().filter(|c| !matches!(c, '{' | '}')).collect();
+    if s.is_empty() || s.contains(char::is_whitespace) {
+        format!("{{{s}}}")
+    } else {
+        s
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::export::tests::{recording, shot_profile};
+    use std::time::Duration;
+
+    #[test]
+    fn decent_shot() {
+        let mut out = vec![];
+        let shot_start = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        write_decent_shot(&mut out, &shot_profile(), &recording(), shot_start).unwrap();
+
+        // Only the two brewing ticks, goals the tick did not control are -1
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "clock 1700000000\n\
+             espresso_elapsed {0.00 0.10}\n\
+             espresso_pressure {0.50 1.25}\n\
+             espresso_flow {1.00 2.00}\n\
+             espresso_weight {0.00 0.50}\n\
+             espresso_flow_weight {0.00 5.00}\n\
+             espresso_temperature_basket {92.50 93.00}\n\
+             espresso_temperature_mix {92.50 93.00}\n\
+             espresso_pressure_goal {2.00 -1.00}\n\
+             espresso_flow_goal {-1.00 2.50}\n\
+             espresso_temperature_goal {93.00 93.00}\n\
+             settings {\n\
+             \tprofile_title {Golden shot}\n\
+             \tespresso_temperature 93\n\
+             \tfinal_desired_shot_weight 36\n\
+             \tdrink_weight 0.5\n\
+             }\n"
+        );
+    }
+}
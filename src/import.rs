@@ -0,0 +1,79 @@
+//! Conversion of profiles from and to other machines. Importers translate into this crate's
+//! profile JSON and parse that, so imported profiles go through the same validation as native
+//! ones.
+
+pub mod decent;
+pub mod gaggiuino;
+
+use crate::profile::ProfileError;
+use json::{object, JsonValue};
+
+/// What could not be translated exactly
+#[derive(Debug, Default, Clone)]
+pub struct ConversionReport {
+    warnings: Vec<String>,
+}
+
+impl ConversionReport {
+    pub fn warn<S: Into<String>>(&mut self, warning: S) {
+        self.warnings.push(warning.into());
+    }
+
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+}
+
+/// Numbers are often stored as strings by other machines
+fn json_number(value: &JsonValue) -> Option<f64> {
+    value
+        .as_f64()
+        .or_else(|| value.as_str().and_then(|s| s.trim().parse().ok()))
+}
+
+/// Stage names must be unique, repeated names get their index appended
+fn unique_name(name: &str, index: usize, taken: &mut Vec<String>) -> String {
+    let name = if taken.iter().any(|n| n == name) {
+        format!("{name} {}", index + 1)
+    } else {
+        name.to_string()
+    };
+    taken.push(name.clone());
+    name
+}
+
+/// Adds an exit trigger to a converted stage, `source` names the step it came from in the
+/// report. Trigger values are whole numbers, which mostly only costs precision. A threshold
+/// rounded to 0 changes the meaning though, `>= 0` fires on the first tick and `<= 0` waits for
+/// an exact 0, so such triggers are left out. Time exits keep their rounding, leaving one out
+/// could keep the stage running forever
+fn push_trigger(
+    stage: &mut JsonValue,
+    source: &str,
+    exit_type: &str,
+    comparison: &str,
+    value: f64,
+    report: &mut ConversionReport,
+) -> Result<(), ProfileError> {
+    let rounded = value.round().max(0.0);
+    if rounded != value {
+        if rounded == 0.0 && exit_type != "time" {
+            report.warn(format!(
+                "{source} {exit_type} exit {comparison} {value} is not a whole number and was \
+                 left out"
+            ));
+            return Ok(());
+        }
+        report.warn(format!(
+            "{source} {exit_type} exit {value} rounded to {rounded}"
+        ));
+    }
+    stage["exit_triggers"]
+        .push(object! {
+            type: exit_type,
+            comparison: comparison,
+            value: rounded as u32,
+            relative: exit_type == "time",
+        })
+        .map_err(ProfileError::from)
+}
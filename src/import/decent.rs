@@ -0,0 +1,333 @@
+//! Decent Espresso DE1 profiles, JSON format version 2.
+//!
+//! Steps become stages controlling `pump` pressure or flow with the step temperature. A `smooth`
+//! transition blends from the previous setpoint over the whole step, `seconds` and `weight`
+//! become exit triggers next to the `exit` condition and the `limiter` becomes a flow limit on
+//! pressure steps or a pressure limit on flow steps.
+
+use super::{json_number, push_trigger, unique_name, ConversionReport};
+use crate::profile::{Profile, ProfileError};
+use json::{object, JsonValue};
+
+// The DE1 app writes this step volume when none was set
+const DEFAULT_STEP_VOLUME: f64 = 100.0;
+
+pub fn profile_from_decent(value: &JsonValue) -> Result<(Profile, ConversionReport), ProfileError> {
+    let mut report = ConversionReport::default();
+
+    let steps = match &value["steps"] {
+        JsonValue::Array(steps) if !steps.is_empty() => steps,
+        JsonValue::Array(_) => {
+            return Err(ProfileError::JsonParsing(
+                "Decent profile has no steps".to_string(),
+            ))
+        }
+        _ => return Err(ProfileError::no_name("steps")),
+    };
+
+    let mut names = vec![];
+    let mut stages = vec![];
+    for (i, step) in steps.iter().enumerate() {
+        stages.push(convert_step(i, step, &mut names, &mut report)?);
+    }
+
+    let temperature = json_number(&steps[0]["temperature"]).unwrap_or_else(|| {
+        report.warn("First step has no temperature, using 93 °C");
+        93.0
+    });
+    let final_weight = json_number(&value["target_weight"]).unwrap_or(0.0);
+    if json_number(&value["target_volume"]).is_some_and(|v| v > 0.0) {
+        report.warn("`target_volume` is not supported, only the target weight ends the shot");
+    }
+    if json_number(&value["tank_temperature"]).is_some_and(|v| v > 0.0) {
+        report.warn("`tank_temperature` is not supported");
+    }
+
+    let converted = object! {
+        name: value["title"].as_str().unwrap_or("Decent profile"),
+        author: value["author"].as_str(),
+        temperature: temperature,
+        final_weight: final_weight,
+        stages: stages,
+    };
+    let profile = Profile::try_from(&converted)?;
+    Ok((profile, report))
+}
+
+fn convert_step(
+    index: usize,
+    step: &JsonValue,
+    names: &mut Vec<String>,
+    report: &mut ConversionReport,
+) -> Result<JsonValue, ProfileError> {
+    let name = unique_name(step["name"].as_str().unwrap_or("step"), index, names);
+
+    let pump = step["pump"].as_str().unwrap_or("pressure");
+    let (control, target, limit_type) = match pump {
+        "pressure" => ("pressure", json_number(&step["pressure"]), "flow"),
+        "flow" => ("flow", json_number(&step["flow"]), "pressure"),
+        x => {
+            return Err(ProfileError::Name(format!(
+                "Step `{name}` has an unknown pump mode `{x}`"
+            )))
+        }
+    };
+    let target = target.ok_or(ProfileError::no_name(pump))?;
+    let seconds = json_number(&step["seconds"]).unwrap_or(0.0);
+
+    let mut stage = object! {
+        name: name.clone(),
+        type: control,
+        dynamics: {
+            points: [[0, target]],
+            over: "stage_time",
+            interpolation: "linear",
+        },
+        exit_triggers: [],
+    };
+
+    match step["transition"].as_str().unwrap_or("fast") {
+        "fast" => {}
+        "smooth" if seconds > 0.0 => stage["transition_blend"] = seconds.into(),
+        "smooth" => report.warn(format!(
+            "Step `{name}` has a smooth transition but no duration, it is fast instead"
+        )),
+        x => report.warn(format!("Step `{name}` has an unknown transition `{x}`")),
+    }
+
+    if let Some(t) = json_number(&step["temperature"]) {
+        stage["temperature"] = t.into();
+    }
+    if step["sensor"].as_str() == Some("water") {
+        report.warn(format!(
+            "Step `{name}` targets the water temperature sensor, the basket sensor is used"
+        ));
+    }
+
+    let source = format!("Step `{name}`");
+    if seconds > 0.0 {
+        push_trigger(&mut stage, &source, "time", ">=", seconds, report)?;
+    }
+    if let Some(w) = json_number(&step["weight"]).filter(|w| *w > 0.0) {
+        push_trigger(&mut stage, &source, "weight", ">=", w, report)?;
+    }
+    if json_number(&step["volume"]).is_some_and(|v| v > 0.0 && v != DEFAULT_STEP_VOLUME) {
+        report.warn(format!(
+            "Step `{name}` has a volume limit, volume exits are not supported"
+        ));
+    }
+
+    let exit = &step["exit"];
+    if exit.is_object() {
+        let exit_type = exit["type"].as_str().unwrap_or("");
+        let comparison = match exit["condition"].as_str() {
+            Some("over") => ">=",
+            Some("under") => "<=",
+            x => {
+                report.warn(format!(
+                    "Step `{name}` has an unknown exit condition `{}`",
+                    x.unwrap_or("")
+                ));
+                ""
+            }
+        };
+        match (exit_type, json_number(&exit["value"])) {
+            ("pressure" | "flow" | "weight", Some(v)) if !comparison.is_empty() => {
+                push_trigger(&mut stage, &source, exit_type, comparison, v, report)?
+            }
+            (x, _) if !comparison.is_empty() => report.warn(format!(
+                "Step `{name}` has an unsupported `{x}` exit condition"
+            )),
+            _ => {}
+        }
+    }
+
+    if let Some(limit) = json_number(&step["limiter"]["value"]).filter(|v| *v > 0.0) {
+        stage["limits"] = JsonValue::Array(vec![object! {
+            type: limit_type,
+            value: limit,
+        }]);
+        if json_number(&step["limiter"]["range"]).is_some_and(|r| r > 0.0) {
+            report.warn(format!(
+                "Step `{name}` limiter range is not supported, the limit is applied as is"
+            ));
+        }
+    }
+
+    Ok(stage)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profile::dynamics::{ControlType, DynamicsInputs, LimitType};
+    use crate::profile::exit_trigger::{ExitComparison as EC, ExitType as ET};
+
+    const SAMPLE: &str = include_str!("../../samples/decent/blooming_ramp.json");
+
+    fn triggers(profile: &Profile, stage: usize) -> Vec<(ET, EC, u32)> {
+        profile.get_stages()[stage]
+            .exit_triggers()
+            .iter()
+            .map(|t| (t.exit_type(), t.exit_comp(), t.value()))
+            .collect()
+    }
+
+    #[test]
+    fn de1_sample() {
+        let (profile, report) = profile_from_decent(&json::parse(SAMPLE).unwrap()).unwrap();
+        let inputs = DynamicsInputs::default();
+        let stages = profile.get_stages();
+
+        assert_eq!(profile.get_name(), Some("Blooming ramp"));
+        assert_eq!(f64::from(profile.get_starting_temp()), 92.0);
+        assert_eq!(f64::from(profile.get_target_weight()), 36.0);
+        let names: Vec<&str> = stages.iter().map(|s| s.name()).collect();
+        assert_eq!(names, ["fill", "bloom", "ramp", "extraction"]);
+
+        // The pump mode picks the control and what the limiter caps
+        let ctrls: Vec<ControlType> = stages.iter().map(|s| s.ctrl()).collect();
+        let targets: Vec<f64> = stages
+            .iter()
+            .map(|s| s.dynamics().run_interpolation(&inputs))
+            .collect();
+        assert_eq!(
+            ctrls,
+            [
+                ControlType::Flow,
+                ControlType::Flow,
+                ControlType::Pressure,
+                ControlType::Flow
+            ]
+        );
+        assert_eq!(targets, [4.0, 0.0, 9.0, 2.2]);
+        let limits: Vec<Vec<(LimitType, f64)>> = stages
+            .iter()
+            .map(|s| {
+                s.limits()
+                    .iter()
+                    .map(|l| (l.kind(), l.value(&inputs)))
+                    .collect()
+            })
+            .collect();
+        assert_eq!(
+            limits,
+            [
+                vec![],
+                vec![],
+                vec![(LimitType::Flow, 3.5)],
+                vec![(LimitType::Pressure, 9.5)]
+            ]
+        );
+
+        // Only the smooth step blends, over its whole duration
+        let blends: Vec<Option<f64>> = stages.iter().map(|s| s.transition_blend()).collect();
+        assert_eq!(blends, [None, None, Some(4.0), None]);
+        let temperatures: Vec<f64> = stages
+            .iter()
+            .map(|s| s.temperature().unwrap().value(&inputs))
+            .collect();
+        assert_eq!(temperatures, [92.0, 92.0, 91.0, 90.0]);
+
+        assert_eq!(
+            triggers(&profile, 0),
+            [
+                (ET::TimeRelative, EC::Greater, 25),
+                (ET::Pressure, EC::Greater, 3)
+            ]
+        );
+        assert_eq!(
+            triggers(&profile, 1),
+            [
+                (ET::TimeRelative, EC::Greater, 30),
+                (ET::Weight, EC::Greater, 4),
+                (ET::Pressure, EC::Smaller, 1)
+            ]
+        );
+        assert_eq!(triggers(&profile, 2), [(ET::TimeRelative, EC::Greater, 4)]);
+        assert_eq!(triggers(&profile, 3), [(ET::TimeRelative, EC::Greater, 60)]);
+
+        // The default step volume of 100 ml is not worth a warning
+        assert_eq!(
+            report.warnings(),
+            [
+                "Step `ramp` limiter range is not supported, the limit is applied as is",
+                "Step `extraction` has a volume limit, volume exits are not supported",
+            ]
+        );
+    }
+
+    #[test]
+    fn smooth_transition_without_duration() {
+        let value = object! {
+            title: "Smooth",
+            steps: [{
+                name: "hold",
+                pump: "pressure",
+                pressure: 9,
+                temperature: 93,
+                transition: "smooth",
+                exit: { type: "flow", condition: "over", value: 3 },
+            }],
+        };
+        let (profile, report) = profile_from_decent(&value).unwrap();
+        assert_eq!(profile.get_stages()[0].transition_blend(), None);
+        assert_eq!(
+            report.warnings(),
+            ["Step `hold` has a smooth transition but no duration, it is fast instead"]
+        );
+    }
+
+    #[test]
+    fn unknown_pump_mode() {
+        let value = object! {
+            title: "Pump",
+            steps: [{ name: "hold", pump: "power", pressure: 9, seconds: 10 }],
+        };
+        assert!(profile_from_decent(&value).is_err());
+    }
+
+    #[test]
+    fn fractional_exit_thresholds() {
+        let step = |exit: JsonValue| {
+            object! {
+                name: "fill",
+                pump: "flow",
+                flow: "4",
+                temperature: "92",
+                seconds: "10",
+                exit: exit,
+            }
+        };
+        let value = object! {
+            title: "Thresholds",
+            target_weight: "36",
+            steps: [
+                step(object! { type: "flow", condition: "over", value: 0.2 }),
+                step(object! { type: "pressure", condition: "over", value: "4.2" }),
+                step(object! { type: "pressure", condition: "under", value: 0.4 }),
+            ],
+        };
+        let (profile, report) = profile_from_decent(&value).unwrap();
+
+        // `flow >= 0` would fire on the first tick, it is left out and reported
+        assert_eq!(triggers(&profile, 0), [(ET::TimeRelative, EC::Greater, 10)]);
+        assert_eq!(
+            triggers(&profile, 1),
+            [
+                (ET::TimeRelative, EC::Greater, 10),
+                (ET::Pressure, EC::Greater, 4)
+            ]
+        );
+        assert_eq!(triggers(&profile, 2), [(ET::TimeRelative, EC::Greater, 10)]);
+
+        let warnings = report.warnings();
+        assert!(warnings
+            .iter()
+            .any(|w| w.contains("`fill`") && w.contains(">= 0.2") && w.contains("left out")));
+        assert!(warnings.iter().any(|w| w.contains("4.2 rounded to 4")));
+        assert!(warnings
+            .iter()
+            .any(|w| w.contains("<= 0.4") && w.contains("left out")));
+    }
+}
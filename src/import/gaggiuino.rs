@@ -0,0 +1,619 @@
+//! Gaggiuino profiles, as stored by its web interface.
+//!
+//! A profile is a list of `phases`, each controlling `"type": "PRESSURE" | "FLOW"` towards a
+//! `target` of `{ "start", "end", "curve", "time" }` with an optional `restriction` of the other
+//! value and `stopConditions` (`time`, `pressureAbove`, `pressureBelow`, `flowAbove`,
+//! `flowBelow`, `weight`, `waterPumpedInPhase`). Times are in ms. A target without `start`
+//! continues from the previous setpoint, which is a transition blend over the target time.
+//! Eased curves are sampled into points and recognised again when exporting.
+
+use super::{json_number, push_trigger, unique_name, ConversionReport};
+use crate::profile::dynamics::{
+    ControlType, Curve, Extrapolation, InputType, LimitType, OutputReference, Point, Setpoint,
+};
+use crate::profile::exit_trigger::{ExitComparison, ExitTrigger, ExitType};
+use crate::profile::{Profile, ProfileError, Stage};
+use json::{array, object, JsonValue};
+
+/// Points an eased target is sampled into
+const CURVE_SEGMENTS: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum CurveStyle {
+    Instant,
+    Linear,
+    EaseIn,
+    EaseOut,
+    EaseInOut,
+}
+
+impl CurveStyle {
+    const RAMPS: [CurveStyle; 4] = [
+        CurveStyle::Linear,
+        CurveStyle::EaseIn,
+        CurveStyle::EaseOut,
+        CurveStyle::EaseInOut,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            CurveStyle::Instant => "INSTANT",
+            CurveStyle::Linear => "LINEAR",
+            CurveStyle::EaseIn => "EASE_IN",
+            CurveStyle::EaseOut => "EASE_OUT",
+            CurveStyle::EaseInOut => "EASE_IN_OUT",
+        }
+    }
+
+    /// Progress of the ramp, both in 0..=1, as computed by Gaggiuino
+    fn ease(&self, pct: f64) -> f64 {
+        match self {
+            CurveStyle::Instant => 1.0,
+            CurveStyle::Linear => pct,
+            CurveStyle::EaseIn => pct.powf(1.675),
+            CurveStyle::EaseOut => 1.0 - (1.0 - pct).powf(1.675),
+            CurveStyle::EaseInOut => 0.5 * (((pct - 0.5) * std::f64::consts::PI).sin() + 1.0),
+        }
+    }
+}
+
+impl TryFrom<&str> for CurveStyle {
+    type Error = ProfileError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "INSTANT" => Ok(Self::Instant),
+            "LINEAR" => Ok(Self::Linear),
+            "EASE_IN" => Ok(Self::EaseIn),
+            "EASE_OUT" => Ok(Self::EaseOut),
+            "EASE_IN_OUT" => Ok(Self::EaseInOut),
+            x => Err(ProfileError::Name(format!("Unexpected curve style: {x}"))),
+        }
+    }
+}
+
+pub fn profile_from_gaggiuino(
+    value: &JsonValue,
+) -> Result<(Profile, ConversionReport), ProfileError> {
+    let mut report = ConversionReport::default();
+
+    let phases = match &value["phases"] {
+        JsonValue::Array(phases) => phases,
+        _ => return Err(ProfileError::no_name("phases")),
+    };
+
+    let mut names = vec![];
+    let mut stages = vec![];
+    for (i, phase) in phases.iter().enumerate() {
+        if phase["skip"].as_bool().unwrap_or(false) {
+            report.warn(format!("Phase {} is skipped and was left out", i + 1));
+            continue;
+        }
+        stages.push(convert_phase(i, phase, &mut names, &mut report)?);
+    }
+    if stages.is_empty() {
+        return Err(ProfileError::JsonParsing(
+            "Gaggiuino profile has no phases".to_string(),
+        ));
+    }
+
+    let temperature = json_number(&value["waterTemperature"]).unwrap_or_else(|| {
+        report.warn("No water temperature, using 93 °C");
+        93.0
+    });
+    let global = &value["globalStopConditions"];
+    let final_weight = json_number(&global["weight"]).unwrap_or(0.0);
+    for key in ["time", "waterPumped"] {
+        if json_number(&global[key]).is_some_and(|v| v > 0.0) {
+            report.warn(format!("Global `{key}` stop condition is not supported"));
+        }
+    }
+
+    let converted = object! {
+        name: value["name"].as_str().unwrap_or("Gaggiuino profile"),
+        temperature: temperature,
+        final_weight: final_weight,
+        stages: stages,
+    };
+    let profile = Profile::try_from(&converted)?;
+    Ok((profile, report))
+}
+
+fn convert_phase(
+    index: usize,
+    phase: &JsonValue,
+    names: &mut Vec<String>,
+    report: &mut ConversionReport,
+) -> Result<JsonValue, ProfileError> {
+    let default_name = format!("Phase {}", index + 1);
+    let name = unique_name(
+        phase["name"].as_str().unwrap_or(&default_name),
+        index,
+        names,
+    );
+
+    let (control, restricted) = match phase["type"].as_str() {
+        Some("PRESSURE") => ("pressure", "flow"),
+        Some("FLOW") => ("flow", "pressure"),
+        Some(x) => {
+            return Err(ProfileError::Name(format!(
+                "Phase `{name}` has an unknown type `{x}`"
+            )))
+        }
+        None => return Err(ProfileError::no_name("type")),
+    };
+
+    let target = &phase["target"];
+    let end = json_number(&target["end"]).ok_or(ProfileError::no_name("end"))?;
+    let seconds = json_number(&target["time"]).unwrap_or(0.0) / 1000.0;
+    let curve = match target["curve"].as_str() {
+        Some(c) => CurveStyle::try_from(c)?,
+        None => CurveStyle::Instant,
+    };
+
+    let mut stage = object! {
+        name: name.clone(),
+        type: control,
+        dynamics: {
+            points: [[0, end]],
+            over: "stage_time",
+            interpolation: "linear",
+        },
+        exit_triggers: [],
+    };
+    match (curve, json_number(&target["start"])) {
+        (CurveStyle::Instant, _) => {}
+        _ if seconds <= 0.0 => {}
+        (CurveStyle::Linear, Some(start)) => {
+            stage["dynamics"]["points"] = array![[0, start], [seconds, end]];
+        }
+        (curve, Some(start)) => {
+            let points: Vec<JsonValue> = (0..=CURVE_SEGMENTS)
+                .map(|i| {
+                    let pct = i as f64 / CURVE_SEGMENTS as f64;
+                    array![pct * seconds, start + (end - start) * curve.ease(pct)]
+                })
+                .collect();
+            stage["dynamics"]["points"] = points.into();
+        }
+        (curve, None) => {
+            if curve != CurveStyle::Linear {
+                report.warn(format!(
+                    "Phase `{name}` continues from the previous setpoint, its {} curve is \
+                     blended linearly",
+                    curve.name()
+                ));
+            }
+            stage["transition_blend"] = seconds.into();
+        }
+    }
+
+    if let Some(restriction) = json_number(&phase["restriction"]).filter(|v| *v > 0.0) {
+        stage["limits"] = JsonValue::Array(vec![object! {
+            type: restricted,
+            value: restriction,
+        }]);
+    }
+
+    let stop = &phase["stopConditions"];
+    let source = format!("Phase `{name}`");
+    if let Some(ms) = json_number(&stop["time"]).filter(|v| *v > 0.0) {
+        push_trigger(&mut stage, &source, "time", ">=", ms / 1000.0, report)?;
+    }
+    for (key, exit_type, comparison) in [
+        ("pressureAbove", "pressure", ">="),
+        ("pressureBelow", "pressure", "<="),
+        ("flowAbove", "flow", ">="),
+        ("flowBelow", "flow", "<="),
+        ("weight", "weight", ">="),
+    ] {
+        if let Some(v) = json_number(&stop[key]).filter(|v| *v > 0.0) {
+            push_trigger(&mut stage, &source, exit_type, comparison, v, report)?;
+        }
+    }
+    if json_number(&stop["waterPumpedInPhase"]).is_some_and(|v| v > 0.0) {
+        report.warn(format!(
+            "Phase `{name}` pumped water stop condition is not supported"
+        ));
+    }
+
+    Ok(stage)
+}
+
+pub fn profile_to_gaggiuino(profile: &Profile) -> (JsonValue, ConversionReport) {
+    let mut report = ConversionReport::default();
+    let temperature = f64::from(profile.get_starting_temp());
+
+    let phases: Vec<JsonValue> = profile
+        .get_stages()
+        .iter()
+        .enumerate()
+        .filter_map(|(i, stage)| export_stage(i, stage, profile, temperature, &mut report))
+        .collect();
+
+    if profile.get_ramp_rates().is_some() {
+        report.warn("Ramp rates are not supported");
+    }
+    let final_weight = f64::from(profile.get_target_weight());
+    let mut global = JsonValue::new_object();
+    if final_weight > 0.0 {
+        global["weight"] = final_weight.into();
+    }
+
+    let value = object! {
+        name: profile.get_name().unwrap_or("Untitled"),
+        waterTemperature: temperature,
+        globalStopConditions: global,
+        phases: phases,
+    };
+    (value, report)
+}
+
+fn export_stage(
+    index: usize,
+    stage: &Stage,
+    profile: &Profile,
+    temperature: f64,
+    report: &mut ConversionReport,
+) -> Option<JsonValue> {
+    let name = stage.name();
+    let (phase_type, restricted) = match stage.ctrl() {
+        ControlType::Pressure => ("PRESSURE", LimitType::Flow),
+        ControlType::Flow => ("FLOW", LimitType::Pressure),
+        ctrl => {
+            report.warn(format!(
+                "Stage `{name}` controls {}, it was left out",
+                ctrl.name()
+            ));
+            return None;
+        }
+    };
+    let Some(target) = export_target(stage, report) else {
+        report.warn(format!(
+            "Stage `{name}` dynamics cannot be expressed as a phase, it was left out"
+        ));
+        return None;
+    };
+
+    let mut phase = object! {
+        name: name,
+        type: phase_type,
+        target: target,
+        stopConditions: {},
+    };
+
+    let limits = if stage.limits().is_empty() {
+        profile.get_default_limits()
+    } else {
+        stage.limits()
+    };
+    for limit in limits {
+        match limit.setpoint() {
+            Setpoint::Constant(v) if limit.kind() == restricted => {
+                phase["restriction"] = (*v).into()
+            }
+            _ => report.warn(format!(
+                "Stage `{name}` {} limit is not supported",
+                limit.kind().name()
+            )),
+        }
+    }
+
+    for trigger in stage.exit_triggers() {
+        export_trigger(index, name, trigger, &mut phase["stopConditions"], report);
+    }
+
+    match stage.temperature() {
+        None => {}
+        Some(Setpoint::Constant(t)) if *t == temperature => {}
+        Some(_) => report.warn(format!(
+            "Stage `{name}` temperature differs from the profile, the profile temperature is used"
+        )),
+    }
+    if stage.ramp_rates().is_some() {
+        report.warn(format!("Stage `{name}` ramp rates are not supported"));
+    }
+
+    Some(phase)
+}
+
+fn export_target(stage: &Stage, report: &mut ConversionReport) -> Option<JsonValue> {
+    let dynamics = stage.dynamics();
+    let Curve::Points(curve) = dynamics.curve() else {
+        return None;
+    };
+    if dynamics.input_type() != InputType::StageTime
+        || dynamics.output_reference() != OutputReference::Absolute
+    {
+        return None;
+    }
+    if curve.extrapolation() != Extrapolation::Clamp {
+        report.warn(format!(
+            "Stage `{}` curve extrapolation is not supported, it holds the last point",
+            stage.name()
+        ));
+    }
+
+    let points = curve.points();
+    let (first, last) = (points[0], points[points.len() - 1]);
+    if points.len() == 1 || last.x <= first.x {
+        return Some(match stage.transition_blend() {
+            Some(blend) => object! {
+                end: last.y,
+                curve: CurveStyle::Linear.name(),
+                time: blend * 1000.0,
+            },
+            None => object! {
+                end: last.y,
+                curve: CurveStyle::Instant.name(),
+                time: 0,
+            },
+        });
+    }
+
+    if first.x > 0.0 {
+        report.warn(format!(
+            "Stage `{}` curve starts after {} s, the phase ramps from the start",
+            stage.name(),
+            first.x
+        ));
+    }
+    if stage.transition_blend().is_some() {
+        report.warn(format!(
+            "Stage `{}` transition blend is not supported on a ramp",
+            stage.name()
+        ));
+    }
+    let curve = match CurveStyle::RAMPS.into_iter().find(|c| follows(points, *c)) {
+        Some(c) => c,
+        None => {
+            report.warn(format!(
+                "Stage `{}` curve is approximated by a linear ramp from its first to last point",
+                stage.name()
+            ));
+            CurveStyle::Linear
+        }
+    };
+    Some(object! {
+        start: first.y,
+        end: last.y,
+        curve: curve.name(),
+        time: (last.x - first.x) * 1000.0,
+    })
+}
+
+/// Whether every point lies on `curve` ramping from the first point to the last
+fn follows(points: &[Point], curve: CurveStyle) -> bool {
+    let (first, last) = (points[0], points[points.len() - 1]);
+    points.iter().all(|p| {
+        let pct = (p.x - first.x) / (last.x - first.x);
+        let expected = first.y + (last.y - first.y) * curve.ease(pct);
+        (p.y - expected).abs() < 1e-6
+    })
+}
+
+fn export_trigger(
+    index: usize,
+    name: &str,
+    trigger: &ExitTrigger,
+    stop: &mut JsonValue,
+    report: &mut ConversionReport,
+) {
+    if trigger
+        .target_stage()
+        .is_some_and(|t| t as usize != index + 1)
+    {
+        report.warn(format!(
+            "Stage `{name}` exit to another stage than the next is not supported"
+        ));
+        return;
+    }
+
+    use ExitComparison as EC;
+    let above = matches!(trigger.exit_comp(), EC::Greater | EC::GreaterStrict);
+    let value = trigger.value() as f64;
+    let (key, value) = match (trigger.exit_type(), above) {
+        (ExitType::TimeRelative, true) => ("time", value * 1000.0),
+        (ExitType::Pressure, true) => ("pressureAbove", value),
+        (ExitType::Pressure, false) => ("pressureBelow", value),
+        (ExitType::Flow, true) => ("flowAbove", value),
+        (ExitType::Flow, false) => ("flowBelow", value),
+        (ExitType::Weight, true) => ("weight", value),
+        (exit_type, _) => {
+            report.warn(format!(
+                "Stage `{name}` {exit_type:?} exit trigger is not supported"
+            ));
+            return;
+        }
+    };
+    if stop.has_key(key) {
+        report.warn(format!(
+            "Stage `{name}` has several `{key}` exit triggers, only the first is kept"
+        ));
+    } else {
+        stop[key] = value.into();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLES: [(&str, &str); 3] = [
+        (
+            "adaptive",
+            include_str!("../../samples/gaggiuino/adaptive.json"),
+        ),
+        (
+            "londinium",
+            include_str!("../../samples/gaggiuino/londinium.json"),
+        ),
+        (
+            "flow_profile",
+            include_str!("../../samples/gaggiuino/flow_profile.json"),
+        ),
+    ];
+
+    /// Numbers are compared by value, exported times are computed back from seconds
+    fn same(a: &JsonValue, b: &JsonValue) -> bool {
+        match (a, b) {
+            (JsonValue::Object(a), JsonValue::Object(b)) => {
+                let keys = |o: &json::object::Object| {
+                    let mut keys: Vec<String> = o
+                        .iter()
+                        .filter(|(_, v)| !v.is_null())
+                        .map(|(k, _)| k.to_string())
+                        .collect();
+                    keys.sort();
+                    keys
+                };
+                keys(a) == keys(b) && keys(a).iter().all(|k| same(&a[k.as_str()], &b[k.as_str()]))
+            }
+            (JsonValue::Array(a), JsonValue::Array(b)) => {
+                a.len() == b.len() && a.iter().zip(b).all(|(a, b)| same(a, b))
+            }
+            _ => match (a.as_f64(), b.as_f64()) {
+                (Some(a), Some(b)) => (a - b).abs() < 1e-9,
+                _ => a == b,
+            },
+        }
+    }
+
+    /// What a phase means, an instant target has no start nor duration
+    fn phase_meaning(phase: &JsonValue) -> JsonValue {
+        let target = &phase["target"];
+        let mut meaning = object! {
+            type: phase["type"].clone(),
+            target: {
+                end: target["end"].clone(),
+                curve: target["curve"].clone(),
+            },
+            restriction: phase["restriction"].clone(),
+            stopConditions: phase["stopConditions"].clone(),
+        };
+        if target["curve"] != "INSTANT" {
+            meaning["target"]["start"] = target["start"].clone();
+            meaning["target"]["time"] = target["time"].clone();
+        }
+        meaning
+    }
+
+    #[test]
+    fn samples_round_trip() {
+        for (name, source) in SAMPLES {
+            let original = json::parse(source).unwrap();
+            let (profile, report) = profile_from_gaggiuino(&original).unwrap();
+            assert!(
+                report.warnings().is_empty(),
+                "{name}: {:?}",
+                report.warnings()
+            );
+            let (exported, report) = profile_to_gaggiuino(&profile);
+            assert!(
+                report.warnings().is_empty(),
+                "{name}: {:?}",
+                report.warnings()
+            );
+
+            assert_eq!(exported["name"], original["name"], "{name}");
+            assert!(same(
+                &exported["waterTemperature"],
+                &original["waterTemperature"]
+            ));
+            assert!(
+                same(
+                    &exported["globalStopConditions"],
+                    &original["globalStopConditions"]
+                ),
+                "{name}: {}",
+                exported["globalStopConditions"]
+            );
+
+            let (original, exported) = (&original["phases"], &exported["phases"]);
+            assert_eq!(exported.len(), original.len(), "{name}");
+            for (a, b) in original.members().zip(exported.members()) {
+                let (a, b) = (phase_meaning(a), phase_meaning(b));
+                assert!(same(&a, &b), "{name}:\n{}\n{}", a.pretty(2), b.pretty(2));
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip_is_stable() {
+        for (name, source) in SAMPLES {
+            let (profile, _) = profile_from_gaggiuino(&json::parse(source).unwrap()).unwrap();
+            let (once, _) = profile_to_gaggiuino(&profile);
+            let (profile, _) = profile_from_gaggiuino(&once).unwrap();
+            let (twice, _) = profile_to_gaggiuino(&profile);
+            assert_eq!(once, twice, "{name}");
+        }
+    }
+
+    #[test]
+    fn eased_curves_are_recognised() {
+        for curve in ["EASE_IN", "EASE_OUT", "EASE_IN_OUT", "LINEAR"] {
+            let original = object! {
+                name: curve,
+                waterTemperature: 93,
+                phases: [{
+                    type: "PRESSURE",
+                    target: { start: 2, end: 9, curve: curve, time: 7000 },
+                    stopConditions: { time: 10000 },
+                }],
+            };
+            let (profile, _) = profile_from_gaggiuino(&original).unwrap();
+            let points = match profile.get_stages()[0].dynamics().curve() {
+                Curve::Points(c) => c.points().len(),
+                _ => unreachable!(),
+            };
+            let expected = if curve == "LINEAR" {
+                2
+            } else {
+                CURVE_SEGMENTS + 1
+            };
+            assert_eq!(points, expected, "{curve}");
+
+            let (exported, report) = profile_to_gaggiuino(&profile);
+            assert!(
+                report.warnings().is_empty(),
+                "{curve}: {:?}",
+                report.warnings()
+            );
+            let target = &exported["phases"][0]["target"];
+            assert_eq!(target["curve"], curve);
+            assert!(same(target, &original["phases"][0]["target"]), "{target}");
+        }
+    }
+
+    #[test]
+    fn lossy_conversions_are_reported() {
+        let original = object! {
+            name: "Lossy",
+            waterTemperature: 93,
+            globalStopConditions: { waterPumped: 60 },
+            phases: [
+                {
+                    type: "FLOW",
+                    target: { end: 4, curve: "EASE_IN", time: 3000 },
+                    stopConditions: { flowAbove: 0.3, pressureAbove: 2.6, waterPumpedInPhase: 20 },
+                },
+                {
+                    type: "PRESSURE",
+                    target: { end: 9, curve: "INSTANT" },
+                    skip: true,
+                },
+            ],
+        };
+        let (profile, report) = profile_from_gaggiuino(&original).unwrap();
+        assert_eq!(profile.get_stages().len(), 1);
+        assert_eq!(report.warnings().len(), 6, "{:?}", report.warnings());
+
+        let (exported, _) = profile_to_gaggiuino(&profile);
+        let phase = &exported["phases"][0];
+        assert_eq!(phase["target"]["curve"], "LINEAR");
+        assert!(same(
+            &phase["stopConditions"],
+            &object! { pressureAbove: 3 }
+        ));
+    }
+}
@@ -7,14 +7,42 @@ macro_rules! json_val_to_obj_tryfrom {
     };
 }
 
+mod clock;
+mod control;
 mod profile;
+mod recorder;
 //mod sampler;
 mod engine;
+mod export;
+mod import;
 mod sensor;
 
+use crate::clock::{Clock, ManualClock};
+use crate::control::PidConfig;
 use crate::engine::*;
+use crate::import::ConversionReport;
 use crate::profile::{FromJson, Profile};
-use crate::sensor::{Driver, DummySensorState};
+use crate::recorder::ShotRecording;
+use crate::sensor::{Driver, DummySensorState, LimitEnforcement};
+
+/// Minimal sink for the `log` facade, the level is read from `RUST_LOG` (default `info`)
+struct StdoutLogger;
+
+impl log::Log for StdoutLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            println!("[{} {}] {}", record.level(), record.target(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StdoutLogger = StdoutLogger;
 
 static PROFILE_JSON: &str = r#"{
     "name": "E61 with dropping pressure",
@@ -88,43 +116,216 @@ static PROFILE_JSON: &str = r#"{
 }"#;
 
 fn main() {
-    let doc = json::parse(PROFILE_JSON).unwrap();
-    let mut profile = Profile::parse_value(&doc).unwrap();
+    let level = std::env::var("RUST_LOG")
+        .ok()
+        .and_then(|l| l.parse().ok())
+        .unwrap_or(log::LevelFilter::Info);
+    log::set_logger(&LOGGER).unwrap();
+    log::set_max_level(level);
+
+    let mut profile = match load_profile() {
+        Ok(profile) => profile,
+        Err(e) => {
+            log::error!("Loading the profile failed: {e}");
+            return;
+        }
+    };
+
+    // Without auto purge the shot stays done, unless purging was asked for
+    let auto_purge = profile.auto_purge();
+    let purge = std::env::args().any(|a| a == "--purge");
 
-    let driver = Driver::<DummySensorState>::default();
+    // The dummy hardware has no native limit support. With `--software-control` it only takes
+    // motor power, like a machine without its own pressure and flow loops
+    let mut driver = Driver::<DummySensorState>::default()
+        .with_limit_enforcement(LimitEnforcement::DEFAULT_SOFTWARE);
+    if std::env::args().any(|a| a == "--software-control") {
+        driver = driver.with_software_control(
+            PidConfig::new(10.0, 5.0, 0.0),
+            PidConfig::new(12.0, 6.0, 0.0).with_derivative_filter(0.1),
+        );
+    }
     let sensor_data = driver.sensor_data() as *const DummySensorState as *mut DummySensorState;
-    let engine_idle = ProfileEngineIdle::try_new(&mut profile, driver).unwrap();
+    // Simulated time, so the shot runs as fast as the machine can step it
+    let clock = ManualClock::default();
+    let mut engine_idle = ProfileEngineIdle::try_new(&mut profile, driver)
+        .unwrap()
+        .with_clock(clock.clone())
+        .with_recording(4096)
+        .with_purge(
+            PurgeSettings::default()
+                .with_piston_position(100.0)
+                .with_speed(10.0)
+                .with_timeout(
+                    arg_secs("--purge-timeout").unwrap_or(std::time::Duration::from_secs(10)),
+                ),
+        );
+    engine_idle.add_observer(|event: &EngineEvent| {
+        if let EngineEvent::ProfileEnded(reason) = event {
+            log::info!("Shot ended: {reason:?}");
+        }
+    });
+
+    // Simulated barista commands: a one second pause, skipping or jumping to a stage and an abort
+    let pause_at = arg_secs("--pause-at");
+    let abort_at = arg_secs("--abort-at");
+    let mut skip_at = arg_secs("--skip-at");
+    let goto = arg("--goto");
+    let mut goto_at = goto.as_ref().and(arg_secs("--goto-at"));
 
-    println!("Starting engine");
+    log::info!("Starting engine");
+    let shot_start = std::time::SystemTime::now();
     let mut engine = engine_idle.start();
-    println!("The engine is in state: {:?}", engine.get_state());
-    loop {
+    log::debug!("The engine is in state: {:?}", engine.get_state());
+    let recording = loop {
         engine = match engine.step() {
-            EngineStepResult::Next(e) => {
-                if e.get_state() == ProfileState::Done {
-                    break;
-                } else {
-                    e
+            EngineStepResult::Next(mut e) => {
+                if e.get_state() == ProfileState::Done && !auto_purge {
+                    if !purge {
+                        break e.take_recording();
+                    }
+                    if let Err(err) = e.purge() {
+                        log::warn!("Purging failed: {err}");
+                    }
                 }
+                e
             }
-            EngineStepResult::Finished(_) => break,
+            EngineStepResult::Finished(mut e) => break e.take_recording(),
             EngineStepResult::Error(e) => {
-                println!("No Stages in profile!!! Error: `{e}`");
+                log::error!("No Stages in profile!!! Error: `{e}`");
                 return;
             }
         };
-        std::thread::sleep(std::time::Duration::from_millis(50));
+        clock.advance(std::time::Duration::from_millis(50));
+        let now = clock.now();
+        if abort_at.is_some_and(|t| now >= t) {
+            break engine.abort().take_recording();
+        }
+        if skip_at.is_some_and(|t| now >= t) {
+            skip_at = None;
+            if let Err(e) = engine.skip_stage() {
+                log::warn!("Skipping the stage failed: {e}");
+            }
+        }
+        if let (Some(stage), Some(t)) = (&goto, goto_at) {
+            if now >= t {
+                goto_at = None;
+                // Stages are given by name, or by index when the argument is a number
+                let result = match stage.parse::<u8>() {
+                    Ok(i) => engine.goto_stage(i),
+                    Err(_) => engine.goto_stage(stage.as_str()),
+                };
+                if let Err(e) = result {
+                    log::warn!("Going to stage `{stage}` failed: {e}");
+                }
+            }
+        }
+        if let Some(t) = pause_at {
+            let resume_at = t + std::time::Duration::from_secs(1);
+            if now >= t && now < resume_at {
+                engine.pause();
+            } else if now >= resume_at && engine.is_paused() {
+                engine.resume();
+            }
+        }
         // We fake the piston moving 1% each step to show the piston position sampling capabilities
-        println!("The engine is in state: {:?}", engine.get_state());
-        if engine.get_state() == ProfileState::Brewing {
+        log::debug!("The engine is in state: {:?}", engine.get_state());
+        let moving = matches!(
+            engine.get_state(),
+            ProfileState::Brewing | ProfileState::Purging
+        );
+        if moving && !engine.is_paused() {
             unsafe {
                 let cur_pos = *(*sensor_data).piston_position;
                 *(*sensor_data).piston_position = (cur_pos + 1.0).min(100.0);
-                println!("Piston: {}", (*sensor_data).piston_position)
+                log::trace!("Piston: {}", (*sensor_data).piston_position)
             }
         }
+    };
+    if let Some(recording) = &recording {
+        log::info!(
+            "Recorded {} samples ({} dropped)",
+            recording.samples().len(),
+            recording.dropped()
+        );
     }
-    println!("Profile execution finished.");
+    if let Err(e) = export_shot(&profile, recording.as_ref(), shot_start) {
+        log::error!("Exporting the shot failed: {e}");
+    }
+    log::info!("Profile execution finished.");
     //println!("Profile allocated 0x{.2} bytes({} kB) of ram for all {} stages combined",
     //    generator.memoryUsed, generator.memoryUsed / 1024, max_profile.stages_len);
 }
+
+/// `--decent <path>` or `--gaggiuino <path>` run an imported profile instead of the built in
+/// one, `--to-gaggiuino <path>` also writes the profile in Gaggiuino's format
+fn load_profile() -> Result<Profile, String> {
+    let read = |path: &str| {
+        std::fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|text| json::parse(&text).map_err(|e| e.to_string()))
+            .map_err(|e| format!("{path}: {e}"))
+    };
+
+    let profile = if let Some(path) = &arg("--decent") {
+        let (profile, report) =
+            import::decent::profile_from_decent(&read(path)?).map_err(|e| format!("{path}: {e}"))?;
+        log_report(path, &report);
+        profile
+    } else if let Some(path) = &arg("--gaggiuino") {
+        let (profile, report) = import::gaggiuino::profile_from_gaggiuino(&read(path)?)
+            .map_err(|e| format!("{path}: {e}"))?;
+        log_report(path, &report);
+        profile
+    } else {
+        let doc = json::parse(PROFILE_JSON).map_err(|e| e.to_string())?;
+        Profile::parse_value(&doc).map_err(|e| e.to_string())?
+    };
+
+    if let Some(path) = &arg("--to-gaggiuino") {
+        let (value, report) = import::gaggiuino::profile_to_gaggiuino(&profile);
+        log_report(path, &report);
+        std::fs::write(path, value.pretty(2)).map_err(|e| format!("{path}: {e}"))?;
+    }
+    Ok(profile)
+}
+
+/// The value following `name` on the command line
+fn arg(name: &str) -> Option<String> {
+    let mut args = std::env::args().skip_while(|a| a != name);
+    args.next()?;
+    args.next()
+}
+
+/// A command line duration in seconds of engine time
+fn arg_secs(name: &str) -> Option<std::time::Duration> {
+    arg(name)
+        .and_then(|v| v.parse().ok())
+        .map(std::time::Duration::from_secs_f64)
+}
+
+fn log_report(path: &str, report: &ConversionReport) {
+    for warning in report.warnings() {
+        log::warn!("{path}: {warning}");
+    }
+}
+
+fn export_shot(
+    profile: &Profile,
+    recording: Option<&ShotRecording>,
+    shot_start: std::time::SystemTime,
+) -> std::io::Result<()> {
+    let dir = std::env::temp_dir();
+    let mut stages = std::fs::File::create(dir.join("shot_stages.csv"))?;
+    export::write_stage_logs_csv(&mut stages, profile)?;
+    if let Some(recording) = recording {
+        let mut samples = std::fs::File::create(dir.join("shot_samples.csv"))?;
+        export::write_recording_csv(&mut samples, recording)?;
+        let mut shot = std::fs::File::create(dir.join("shot.shot"))?;
+        export::visualizer::write_decent_shot(&mut shot, profile, recording, shot_start)?;
+    }
+    let mut json = std::fs::File::create(dir.join("shot.json"))?;
+    export::write_shot_json(&mut json, profile, recording)?;
+    log::info!("Shot exported to {}", dir.display());
+    Ok(())
+}
@@ -1,6 +1,11 @@
 use crate::profile::ProfileError;
 use json::object::Object;
 use json::JsonValue;
+use std::cell::Cell;
+
+mod expression;
+
+pub use expression::Expression;
 
 #[repr(u8)]
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -11,6 +16,17 @@ pub enum ControlType {
     PistonPosition = 3u8,
 }
 
+impl ControlType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ControlType::Pressure => "pressure",
+            ControlType::Flow => "flow",
+            ControlType::Power => "power",
+            ControlType::PistonPosition => "piston_position",
+        }
+    }
+}
+
 impl TryFrom<&str> for ControlType {
     type Error = ProfileError;
 
@@ -31,6 +47,11 @@ pub enum InputType {
     Time = 0u8,
     PistonPosition = 1u8,
     Weight = 2u8,
+    Pressure = 3u8,
+    Flow = 4u8,
+    Temperature = 5u8,
+    Volume = 6u8,
+    StageTime = 7u8,
 }
 
 impl TryFrom<&str> for InputType {
@@ -41,11 +62,103 @@ impl TryFrom<&str> for InputType {
             "time" => Ok(Self::Time),
             "piston_position" => Ok(Self::PistonPosition),
             "weight" => Ok(Self::Weight),
+            "pressure" => Ok(Self::Pressure),
+            "flow" => Ok(Self::Flow),
+            "temperature" => Ok(Self::Temperature),
+            "volume" => Ok(Self::Volume),
+            "stage_time" => Ok(Self::StageTime),
             x => Err(ProfileError::Name(format!("Unexpected name: {x}"))),
         }
     }
 }
 
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Extrapolation {
+    #[default]
+    Clamp = 0u8,
+    Linear = 1u8,
+    Repeat = 2u8,
+}
+
+impl TryFrom<&str> for Extrapolation {
+    type Error = ProfileError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "clamp" => Ok(Self::Clamp),
+            "linear" => Ok(Self::Linear),
+            "repeat" => Ok(Self::Repeat),
+            x => Err(ProfileError::Name(format!("Unexpected name: {x}"))),
+        }
+    }
+}
+
+/// How sampled values relate to the controlled value measured when the stage was entered
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum OutputReference {
+    #[default]
+    Absolute = 0u8,
+    Offset = 1u8,
+    Fraction = 2u8,
+}
+
+impl OutputReference {
+    pub fn apply(&self, sampled: f64, entry_value: f64) -> f64 {
+        match self {
+            OutputReference::Absolute => sampled,
+            OutputReference::Offset => entry_value + sampled,
+            OutputReference::Fraction => entry_value * sampled,
+        }
+    }
+}
+
+impl TryFrom<&JsonValue> for OutputReference {
+    type Error = ProfileError;
+
+    fn try_from(value: &JsonValue) -> Result<Self, Self::Error> {
+        match value {
+            JsonValue::Boolean(true) => Ok(Self::Offset),
+            JsonValue::Boolean(false) => Ok(Self::Absolute),
+            v => match v.as_str().ok_or(ProfileError::unexpected_type("string"))? {
+                "absolute" => Ok(Self::Absolute),
+                "offset" => Ok(Self::Offset),
+                "fraction" => Ok(Self::Fraction),
+                x => Err(ProfileError::Name(format!("Unexpected name: {x}"))),
+            },
+        }
+    }
+}
+
+/// Snapshot of every value a dynamics can be driven by, gathered by the engine each tick
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct DynamicsInputs {
+    pub time: f64,
+    pub stage_time: f64,
+    pub piston_position: f64,
+    pub weight: f64,
+    pub pressure: f64,
+    pub flow: f64,
+    pub temperature: f64,
+    pub volume: f64,
+}
+
+impl DynamicsInputs {
+    pub fn get(&self, input: InputType) -> f64 {
+        match input {
+            InputType::Time => self.time,
+            InputType::StageTime => self.stage_time,
+            InputType::PistonPosition => self.piston_position,
+            InputType::Weight => self.weight,
+            InputType::Pressure => self.pressure,
+            InputType::Flow => self.flow,
+            InputType::Temperature => self.temperature,
+            InputType::Volume => self.volume,
+        }
+    }
+}
+
 trait InterpolationAlgorithm: std::fmt::Debug {
     fn get_value(&self, points: &[Point], input: f64, current_index: usize) -> f64; // Returns a rate `r` (y = r*x)
 }
@@ -88,10 +201,104 @@ impl TryFrom<&JsonValue> for Point {
     }
 }
 
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LimitType {
+    Pressure = 0u8, // bar
+    Flow = 1u8,     // ml/s
+    Power = 2u8,
+    PistonSpeed = 3u8,
+    Temperature = 4u8, // °C
+}
+
+impl LimitType {
+    pub const ALL: [LimitType; 5] = [
+        LimitType::Pressure,
+        LimitType::Flow,
+        LimitType::Power,
+        LimitType::PistonSpeed,
+        LimitType::Temperature,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            LimitType::Pressure => "pressure",
+            LimitType::Flow => "flow",
+            LimitType::Power => "power",
+            LimitType::PistonSpeed => "piston_speed",
+            LimitType::Temperature => "temperature",
+        }
+    }
+}
+
+impl TryFrom<&str> for LimitType {
+    type Error = ProfileError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "flow" => Ok(Self::Flow),
+            "pressure" => Ok(Self::Pressure),
+            "power" => Ok(Self::Power),
+            "piston_speed" => Ok(Self::PistonSpeed),
+            "temperature" => Ok(Self::Temperature),
+            x => Err(ProfileError::Name(format!(
+                "Limits does not recognize the type value `{x}`"
+            ))),
+        }
+    }
+}
+
+/// A value that is either fixed or follows its own dynamics
 #[derive(Debug)]
-pub enum Limit {
-    Pressure(f64), // bar
-    Flow(f64),     // ml/s
+pub enum Setpoint {
+    Constant(f64),
+    Dynamic(Dynamics),
+}
+
+impl Setpoint {
+    pub fn value(&self, inputs: &DynamicsInputs) -> f64 {
+        match self {
+            Setpoint::Constant(v) => *v,
+            Setpoint::Dynamic(d) => d.run_interpolation(inputs),
+        }
+    }
+}
+
+impl TryFrom<&JsonValue> for Setpoint {
+    type Error = ProfileError;
+
+    fn try_from(value: &JsonValue) -> Result<Self, Self::Error> {
+        if let Some(v) = value.as_f64() {
+            return Ok(Setpoint::Constant(v));
+        }
+        let dynamics = Dynamics::try_from(value)?;
+        if dynamics.output_reference() != OutputReference::Absolute {
+            return Err(ProfileError::JsonParsing(
+                "Only the stage dynamics can be relative to the entry value".to_string(),
+            ));
+        }
+        Ok(Setpoint::Dynamic(dynamics))
+    }
+}
+
+#[derive(Debug)]
+pub struct Limit {
+    kind: LimitType,
+    value: Setpoint,
+}
+
+impl Limit {
+    pub fn kind(&self) -> LimitType {
+        self.kind
+    }
+
+    pub fn value(&self, inputs: &DynamicsInputs) -> f64 {
+        self.value.value(inputs)
+    }
+
+    pub fn setpoint(&self) -> &Setpoint {
+        &self.value
+    }
 }
 
 impl TryFrom<&JsonValue> for Limit {
@@ -105,45 +312,195 @@ impl TryFrom<&Object> for Limit {
     type Error = ProfileError;
 
     fn try_from(value: &Object) -> Result<Self, Self::Error> {
-        let val = value
-            .get("value")
-            .ok_or(ProfileError::no_name("value"))?
-            .as_f64()
-            .ok_or(ProfileError::unexpected_type("float"))?;
-        match value
-            .get("type")
-            .ok_or(ProfileError::no_name("type"))?
-            .as_str()
-            .ok_or(ProfileError::unexpected_type("string"))?
-        {
-            "flow" => Ok(Limit::Flow(val)),
-            "pressure" => Ok(Limit::Pressure(val)),
-            x => Err(ProfileError::Name(format!(
-                "Limits does not recognize the type value `{x}`"
-            ))),
+        let kind = LimitType::try_from(
+            value
+                .get("type")
+                .ok_or(ProfileError::no_name("type"))?
+                .as_str()
+                .ok_or(ProfileError::unexpected_type("string"))?,
+        )?;
+        let value = match (value.get("value"), value.get("dynamics")) {
+            (Some(v), None) => {
+                Setpoint::Constant(v.as_f64().ok_or(ProfileError::unexpected_type("float"))?)
+            }
+            (None, Some(d)) => Setpoint::try_from(d)?,
+            (Some(_), Some(_)) => {
+                return Err(ProfileError::JsonParsing(
+                    "Limits take either a `value` or `dynamics`, not both".to_string(),
+                ))
+            }
+            (None, None) => return Err(ProfileError::no_name("value")),
+        };
+
+        Ok(Self { kind, value })
+    }
+}
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum WaveShape {
+    Square = 0u8,
+    Sine = 1u8,
+    Triangle = 2u8,
+}
+
+impl TryFrom<&str> for WaveShape {
+    type Error = ProfileError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "square" => Ok(Self::Square),
+            "sine" => Ok(Self::Sine),
+            "triangle" => Ok(Self::Triangle),
+            x => Err(ProfileError::Name(format!("Unexpected name: {x}"))),
+        }
+    }
+}
+
+/// A repeating waveform swinging `amplitude` around `offset`. For a square wave, `duty_cycle` is
+/// the fraction of the period spent at the high value, for a triangle it is the fraction spent
+/// rising. A repeating point list is a regular curve with `"extrapolation": "repeat"`.
+///
+/// `offset` defaults to 0, so the wave swings down to `-amplitude`. A pressure or flow pulse that
+/// stays at or above 0 needs `offset` equal to `amplitude`.
+#[derive(Debug, Clone, Copy)]
+pub struct Waveform {
+    shape: WaveShape,
+    amplitude: f64,
+    offset: f64,
+    period: f64,
+    duty_cycle: f64,
+}
+
+impl Waveform {
+    pub fn sample(&self, input: f64) -> f64 {
+        let phase = (input / self.period).rem_euclid(1.0);
+        let unit = match self.shape {
+            WaveShape::Square => {
+                if phase < self.duty_cycle {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            WaveShape::Sine => (phase * std::f64::consts::TAU).sin(),
+            WaveShape::Triangle => {
+                if phase < self.duty_cycle {
+                    -1.0 + 2.0 * phase / self.duty_cycle
+                } else {
+                    1.0 - 2.0 * (phase - self.duty_cycle) / (1.0 - self.duty_cycle)
+                }
+            }
+        };
+        self.offset + self.amplitude * unit
+    }
+}
+
+impl TryFrom<&JsonValue> for Waveform {
+    type Error = ProfileError;
+
+    fn try_from(value: &JsonValue) -> Result<Self, Self::Error> {
+        json_val_to_obj_tryfrom!(value)
+    }
+}
+impl TryFrom<&Object> for Waveform {
+    type Error = ProfileError;
+
+    fn try_from(value: &Object) -> Result<Self, Self::Error> {
+        let get_f64 = |name: &str| {
+            value
+                .get(name)
+                .ok_or(ProfileError::no_name(name))?
+                .as_f64()
+                .ok_or(ProfileError::unexpected_type("f64"))
+        };
+        let shape = WaveShape::try_from(
+            value
+                .get("shape")
+                .ok_or(ProfileError::no_name("shape"))?
+                .as_str()
+                .ok_or(ProfileError::unexpected_type("string"))?,
+        )?;
+        let period = get_f64("period")?;
+        if period <= 0.0 {
+            return Err(ProfileError::JsonParsing(
+                "Period must be greater than 0".to_string(),
+            ));
+        }
+        let duty_cycle = match value.get("duty_cycle") {
+            Some(v) => v.as_f64().ok_or(ProfileError::unexpected_type("f64"))?,
+            None => 0.5,
+        };
+        if duty_cycle <= 0.0 || duty_cycle >= 1.0 {
+            return Err(ProfileError::JsonParsing(
+                "Duty cycle must be between 0 and 1".to_string(),
+            ));
         }
+
+        let offset = match value.get("offset") {
+            Some(v) => v.as_f64().ok_or(ProfileError::unexpected_type("f64"))?,
+            None => 0.0,
+        };
+
+        Ok(Self {
+            shape,
+            amplitude: get_f64("amplitude")?,
+            offset,
+            period,
+            duty_cycle,
+        })
     }
 }
 
 #[derive(Debug)]
-pub struct Dynamics {
-    //control_select: Option<ControlType>, On stage
-    input_select: InputType, //`over`
+pub struct PointCurve {
     interpolation: Box<dyn InterpolationAlgorithm>,
+    extrapolation: Extrapolation,
     //points_len: u8,
     //points: *const Point,
     points: Vec<Point>,
-    //limits: Limits,
+    // Index of the last segment sampled, monotonic inputs usually hit it (or the next) again
+    cursor: Cell<usize>,
 }
 
-impl Dynamics {
-    pub fn input_type(&self) -> InputType {
-        self.input_select
+// Binary searches done by `PointCurve`s when the cursor missed, counted per test thread
+#[cfg(test)]
+thread_local! {
+    static SEARCHES: Cell<usize> = const { Cell::new(0) };
+}
+
+impl PointCurve {
+    pub fn points(&self) -> &[Point] {
+        &self.points
+    }
+
+    pub fn extrapolation(&self) -> Extrapolation {
+        self.extrapolation
     }
 
-    pub fn run_interpolation(&self, input: f64) -> f64 {
+    pub fn sample(&self, input: f64) -> f64 {
+        let first = self.points[0];
+        let last = self.points[self.points.len() - 1];
+        let span = last.x - first.x;
+
+        let input = match self.extrapolation {
+            Extrapolation::Repeat if span > 0.0 => first.x + (input - first.x).rem_euclid(span),
+            _ => input,
+        };
+
         match self.find_current_segment(input) {
             SegmentIndexOrValue::Index(i) => self.interpolation.get_value(&self.points, input, i),
+            // Outside the points, keep following the first/last segment
+            SegmentIndexOrValue::Value(_)
+                if self.extrapolation == Extrapolation::Linear && self.points.len() > 1 =>
+            {
+                let i = if input <= first.x {
+                    1
+                } else {
+                    self.points.len() - 1
+                };
+                self.interpolation.get_value(&self.points, input, i)
+            }
             SegmentIndexOrValue::Value(v) => v,
         }
     }
@@ -153,12 +510,21 @@ impl Dynamics {
             [first] => SegmentIndexOrValue::Value(first.y),
             [first, ..] if first.x >= input => SegmentIndexOrValue::Value(first.y),
             [.., last] if last.x <= input => SegmentIndexOrValue::Value(last.y),
-            arr => SegmentIndexOrValue::Index(
-                arr.iter()
-                    .enumerate()
-                    .find_map(|(i, p)| if p.x >= input { Some(i) } else { None })
-                    .unwrap(),
-            ),
+            arr => {
+                let in_segment = |i: usize| arr[i - 1].x < input && arr[i].x >= input;
+                let cursor = self.cursor.get();
+                let index = if in_segment(cursor) {
+                    cursor
+                } else if cursor + 1 < arr.len() && in_segment(cursor + 1) {
+                    cursor + 1
+                } else {
+                    #[cfg(test)]
+                    SEARCHES.with(|s| s.set(s.get() + 1));
+                    arr.partition_point(|p| p.x < input)
+                };
+                self.cursor.set(index);
+                SegmentIndexOrValue::Index(index)
+            }
         }
     }
 }
@@ -168,32 +534,10 @@ enum SegmentIndexOrValue {
     Value(f64),
 }
 
-impl TryFrom<&JsonValue> for Dynamics {
-    type Error = ProfileError;
-
-    fn try_from(value: &JsonValue) -> Result<Self, Self::Error> {
-        json_val_to_obj_tryfrom!(value)
-    }
-}
-impl TryFrom<&Object> for Dynamics {
+impl TryFrom<&Object> for PointCurve {
     type Error = ProfileError;
 
     fn try_from(value: &Object) -> Result<Self, Self::Error> {
-        let input_select = match value
-            .get("over")
-            .ok_or(ProfileError::no_name("over"))?
-            .as_str()
-            .ok_or(ProfileError::unexpected_type("string"))?
-        {
-            "time" => InputType::Time,
-            "piston_position" => InputType::PistonPosition,
-            "weight" => InputType::Weight,
-            x => {
-                return Err(ProfileError::Name(format!(
-                    "No valid value for type, got `{x}`"
-                )))
-            }
-        };
         let interpolation_name = value
             .get("interpolation")
             .ok_or(ProfileError::no_name("interpolation"))?
@@ -201,6 +545,12 @@ impl TryFrom<&Object> for Dynamics {
             .ok_or(ProfileError::unexpected_type("string"))?;
         let interpolation: Box<dyn InterpolationAlgorithm> =
             get_interpolation_from_name(interpolation_name)?;
+        let extrapolation = match value.get("extrapolation") {
+            Some(v) => Extrapolation::try_from(
+                v.as_str().ok_or(ProfileError::unexpected_type("string"))?,
+            )?,
+            None => Extrapolation::default(),
+        };
         let points: Vec<Point> = value
             .get("points")
             .ok_or(ProfileError::no_name("points"))
@@ -216,11 +566,121 @@ impl TryFrom<&Object> for Dynamics {
                 "Note enough points provided, minimum 1".to_string(),
             ));
         }
+        if points.windows(2).any(|w| w[0].x > w[1].x) {
+            return Err(ProfileError::JsonParsing(
+                "Points must be sorted by ascending x".to_string(),
+            ));
+        }
+        // Extrapolating follows the slope of the end segments, which needs them to have a width
+        let n = points.len();
+        if extrapolation == Extrapolation::Linear
+            && n > 1
+            && (points[0].x == points[1].x || points[n - 2].x == points[n - 1].x)
+        {
+            return Err(ProfileError::JsonParsing(
+                "Linear extrapolation needs distinct x on the first and last two points"
+                    .to_string(),
+            ));
+        }
 
         Ok(Self {
             points,
             interpolation,
+            extrapolation,
+            cursor: Cell::new(1),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum Curve {
+    Points(PointCurve),
+    Periodic(Waveform),
+    Expression(Expression),
+}
+
+#[derive(Debug)]
+pub struct Dynamics {
+    //control_select: Option<ControlType>, On stage
+    input_select: InputType, //`over`
+    output_reference: OutputReference,
+    curve: Curve,
+    //limits: Limits,
+}
+
+impl Dynamics {
+    pub fn input_type(&self) -> InputType {
+        self.input_select
+    }
+
+    pub fn output_reference(&self) -> OutputReference {
+        self.output_reference
+    }
+
+    pub fn curve(&self) -> &Curve {
+        &self.curve
+    }
+
+    pub fn run_interpolation(&self, inputs: &DynamicsInputs) -> f64 {
+        let input = inputs.get(self.input_select);
+        match &self.curve {
+            Curve::Points(c) => c.sample(input),
+            Curve::Periodic(w) => w.sample(input),
+            Curve::Expression(e) => e.evaluate(inputs),
+        }
+    }
+}
+
+impl TryFrom<&JsonValue> for Dynamics {
+    type Error = ProfileError;
+
+    fn try_from(value: &JsonValue) -> Result<Self, Self::Error> {
+        json_val_to_obj_tryfrom!(value)
+    }
+}
+impl TryFrom<&Object> for Dynamics {
+    type Error = ProfileError;
+
+    fn try_from(value: &Object) -> Result<Self, Self::Error> {
+        let output_reference = match value.get("relative") {
+            Some(v) => OutputReference::try_from(v)?,
+            None => OutputReference::default(),
+        };
+
+        if let Some(expression) = value.get("expression") {
+            let source = expression
+                .as_str()
+                .ok_or(ProfileError::unexpected_type("string"))?;
+            // An expression names its own inputs, `over` is only kept for logging
+            let input_select = match value.get("over") {
+                Some(v) => InputType::try_from(
+                    v.as_str().ok_or(ProfileError::unexpected_type("string"))?,
+                )?,
+                None => InputType::Time,
+            };
+            return Ok(Self {
+                input_select,
+                output_reference,
+                curve: Curve::Expression(Expression::compile(source)?),
+            });
+        }
+
+        let input_select = InputType::try_from(
+            value
+                .get("over")
+                .ok_or(ProfileError::no_name("over"))?
+                .as_str()
+                .ok_or(ProfileError::unexpected_type("string"))?,
+        )?;
+        let curve = match value.get("periodic") {
+            Some(periodic) => Curve::Periodic(Waveform::try_from(periodic)?),
+            None => Curve::Points(PointCurve::try_from(value)?),
+        };
+
+        Ok(Self {
             input_select,
+            output_reference,
+            curve,
         })
     }
 }
@@ -235,3 +695,216 @@ fn get_interpolation_from_name(
         ))),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn waveform(json: &str) -> Result<Waveform, ProfileError> {
+        Waveform::try_from(&json::parse(json).unwrap())
+    }
+
+    fn curve(points: &[(f64, f64)], extrapolation: &str) -> Result<PointCurve, ProfileError> {
+        let points: Vec<JsonValue> = points.iter().map(|(x, y)| json::array![*x, *y]).collect();
+        let value = json::object! {
+            points: points,
+            interpolation: "linear",
+            extrapolation: extrapolation,
+        };
+        match &value {
+            JsonValue::Object(o) => PointCurve::try_from(o),
+            _ => unreachable!(),
+        }
+    }
+
+    /// The linear scan `PointCurve` used before the cursor
+    fn scan(points: &[Point], input: f64) -> f64 {
+        let (first, last) = (points[0], points[points.len() - 1]);
+        if points.len() == 1 || first.x >= input {
+            return first.y;
+        }
+        if last.x <= input {
+            return last.y;
+        }
+        let i = (1..points.len())
+            .find(|&i| points[i - 1].x < input && points[i].x >= input)
+            .unwrap();
+        LinearInterpolation.get_value(points, input, i)
+    }
+
+    fn many_points() -> Vec<(f64, f64)> {
+        (0..500)
+            .map(|i| (i as f64 * 0.5, ((i * 37) % 101) as f64))
+            .collect()
+    }
+
+    #[test]
+    fn dynamics_inputs() {
+        let dynamics = |over: &str| {
+            Dynamics::try_from(&json::object! {
+                points: [[0, 0], [10, 10]],
+                over: over,
+                interpolation: "linear",
+            })
+        };
+        let inputs = DynamicsInputs {
+            time: 1.0,
+            stage_time: 2.0,
+            piston_position: 3.0,
+            weight: 4.0,
+            pressure: 5.0,
+            flow: 6.0,
+            temperature: 7.0,
+            volume: 8.0,
+        };
+        for (over, input, value) in [
+            ("time", InputType::Time, 1.0),
+            ("stage_time", InputType::StageTime, 2.0),
+            ("piston_position", InputType::PistonPosition, 3.0),
+            ("weight", InputType::Weight, 4.0),
+            ("pressure", InputType::Pressure, 5.0),
+            ("flow", InputType::Flow, 6.0),
+            ("temperature", InputType::Temperature, 7.0),
+            ("volume", InputType::Volume, 8.0),
+        ] {
+            let d = dynamics(over).unwrap();
+            assert_eq!(d.input_type(), input);
+            assert_eq!(d.run_interpolation(&inputs), value, "over {over}");
+        }
+
+        assert!(dynamics("speed").is_err());
+        let mut no_input = json::object! { points: [[0, 1]], interpolation: "linear" };
+        assert!(Dynamics::try_from(&no_input).is_err());
+        no_input["over"] = 1.into();
+        assert!(Dynamics::try_from(&no_input).is_err());
+    }
+
+    #[test]
+    fn monotonic_sampling_hits_the_cursor() {
+        // Tests share the counter when they run on one thread
+        let searches = || SEARCHES.get();
+        let c = curve(&many_points(), "clamp").unwrap();
+        let before = searches();
+        let mut x = -1.0;
+        while x < 260.0 {
+            c.sample(x);
+            x += 0.1;
+        }
+        assert_eq!(searches() - before, 0);
+
+        // A jump back to the start needs one search, then the cursor follows again
+        for i in 0..100 {
+            c.sample(i as f64 * 0.2);
+        }
+        assert_eq!(searches() - before, 1);
+    }
+
+    #[test]
+    fn sampling_matches_a_linear_scan() {
+        let points = many_points();
+        let c = curve(&points, "clamp").unwrap();
+        let mut inputs: Vec<f64> = points.iter().map(|p| p.0).collect();
+        // Pseudo random inputs in and around the curve, in random order
+        let mut seed = 12345u64;
+        for _ in 0..2000 {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            inputs.push((seed >> 33) as f64 / (1u64 << 31) as f64 * 260.0 - 5.0);
+        }
+        inputs.extend([-1.0, 0.0, 249.5, 300.0]);
+
+        for input in inputs {
+            assert_eq!(c.sample(input), scan(c.points(), input), "input {input}");
+        }
+    }
+
+    #[test]
+    fn sampling_on_repeated_x() {
+        let points = [(0.0, 1.0), (1.0, 2.0), (1.0, 5.0), (2.0, 6.0)];
+        let c = curve(&points, "clamp").unwrap();
+        for input in [0.0, 0.5, 1.0, 1.5, 2.0, 1.0, 0.0] {
+            assert_eq!(c.sample(input), scan(c.points(), input), "input {input}");
+        }
+    }
+
+    #[test]
+    fn linear_extrapolation_needs_wide_end_segments() {
+        let duplicates = [
+            [(0.0, 1.0), (0.0, 2.0), (5.0, 3.0)],
+            [(0.0, 1.0), (5.0, 2.0), (5.0, 3.0)],
+        ];
+        for points in duplicates {
+            assert!(curve(&points, "linear").is_err());
+            // Clamping never looks at the end segments' slope
+            let c = curve(&points, "clamp").unwrap();
+            assert!([-1.0, 0.0, 5.0, 6.0].iter().all(|x| c.sample(*x).is_finite()));
+        }
+
+        let c = curve(&[(0.0, 1.0), (1.0, 2.0), (1.0, 4.0), (2.0, 5.0)], "linear").unwrap();
+        assert_eq!(c.sample(-1.0), 0.0);
+        assert_eq!(c.sample(3.0), 6.0);
+    }
+
+    #[test]
+    fn waveform_offset() {
+        let base = r#""shape": "sine", "amplitude": 1, "period": 4"#;
+        let w = waveform(&format!("{{{base}, \"offset\": 1.5}}")).unwrap();
+        assert_eq!(w.offset, 1.5);
+        assert_eq!(waveform(&format!("{{{base}}}")).unwrap().offset, 0.0);
+        assert!(waveform(&format!("{{{base}, \"offset\": \"1.5\"}}")).is_err());
+    }
+
+    fn assert_samples(w: &Waveform, expected: &[(f64, f64)]) {
+        for &(input, value) in expected {
+            let actual = w.sample(input);
+            assert!((actual - value).abs() < 1e-9, "input {input}: {actual} != {value}");
+        }
+    }
+
+    #[test]
+    fn sine_values() {
+        let w = waveform(r#"{"shape": "sine", "amplitude": 2, "period": 4}"#).unwrap();
+        // Swings negative around the default offset of 0, the phase wraps every period
+        assert_samples(
+            &w,
+            &[(0.0, 0.0), (1.0, 2.0), (2.0, 0.0), (3.0, -2.0), (5.0, 2.0), (-1.0, -2.0)],
+        );
+    }
+
+    #[test]
+    fn square_values() {
+        let w = waveform(
+            r#"{"shape": "square", "amplitude": 3, "offset": 3, "period": 4, "duty_cycle": 0.25}"#,
+        )
+        .unwrap();
+        assert_samples(
+            &w,
+            &[(0.0, 6.0), (0.99, 6.0), (1.0, 0.0), (3.99, 0.0), (4.0, 6.0), (-3.5, 6.0)],
+        );
+    }
+
+    #[test]
+    fn triangle_values() {
+        let w = waveform(r#"{"shape": "triangle", "amplitude": 1, "period": 4}"#).unwrap();
+        assert_samples(&w, &[(0.0, -1.0), (1.0, 0.0), (2.0, 1.0), (3.0, 0.0), (4.0, -1.0)]);
+
+        // A quarter of the period rising, the rest falling
+        let w = waveform(
+            r#"{"shape": "triangle", "amplitude": 1, "period": 4, "duty_cycle": 0.25}"#,
+        )
+        .unwrap();
+        assert_samples(
+            &w,
+            &[(0.5, 0.0), (1.0, 1.0), (2.5, 0.0), (4.0, -1.0), (9.0, 1.0), (-3.0, 1.0)],
+        );
+    }
+
+    #[test]
+    fn duty_cycle_must_be_inside_the_period() {
+        for duty_cycle in ["0", "1", "1.5", "-0.5"] {
+            let json = format!(
+                r#"{{"shape": "square", "amplitude": 1, "period": 4, "duty_cycle": {duty_cycle}}}"#
+            );
+            assert!(waveform(&json).is_err(), "duty cycle {duty_cycle}");
+        }
+    }
+}
@@ -0,0 +1,380 @@
+use super::{DynamicsInputs, InputType};
+use crate::profile::ProfileError;
+
+// Deepest operand stack an expression may need, checked when compiling
+const MAX_STACK: usize = 16;
+// Deepest nesting the parser recurses into, so hostile input cannot overflow the native stack
+const MAX_DEPTH: usize = MAX_STACK;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Function {
+    Min,
+    Max,
+    Clamp,
+    Abs,
+    Sqrt,
+    Exp,
+    Ln,
+    Sin,
+    Cos,
+}
+
+impl Function {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "min" => Self::Min,
+            "max" => Self::Max,
+            "clamp" => Self::Clamp,
+            "abs" => Self::Abs,
+            "sqrt" => Self::Sqrt,
+            "exp" => Self::Exp,
+            "ln" => Self::Ln,
+            "sin" => Self::Sin,
+            "cos" => Self::Cos,
+            _ => return None,
+        })
+    }
+
+    fn arity(&self) -> usize {
+        match self {
+            Self::Min | Self::Max => 2,
+            Self::Clamp => 3,
+            _ => 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Const(f64),
+    Var(InputType),
+    Neg,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Pow,
+    Call(Function),
+}
+
+impl Op {
+    // Operands popped and pushed, used to validate the stack depth at compile time
+    fn stack_effect(&self) -> (usize, usize) {
+        match self {
+            Op::Const(_) | Op::Var(_) => (0, 1),
+            Op::Neg => (1, 1),
+            Op::Add | Op::Sub | Op::Mul | Op::Div | Op::Pow => (2, 1),
+            Op::Call(f) => (f.arity(), 1),
+        }
+    }
+}
+
+/// An arithmetic expression over the engine inputs, compiled to postfix form so evaluating it
+/// every tick needs no allocation.
+///
+/// Supports `+ - * / ^`, parentheses, the variables `t`/`time`, `stage_time`,
+/// `piston_position`, `weight`, `pressure`, `flow`, `temperature` and `volume`, and the
+/// functions `min`, `max`, `clamp`, `abs`, `sqrt`, `exp`, `ln`, `sin` and `cos`.
+#[derive(Debug, Clone)]
+pub struct Expression {
+    ops: Vec<Op>,
+}
+
+impl Expression {
+    pub fn compile(source: &str) -> Result<Self, ProfileError> {
+        let mut parser = Parser {
+            src: source.as_bytes(),
+            pos: 0,
+            depth: 0,
+            ops: vec![],
+        };
+        parser.expr()?;
+        parser.skip_whitespace();
+        if parser.pos != parser.src.len() {
+            return Err(parser.error("unexpected trailing input"));
+        }
+
+        let mut depth = 0usize;
+        for op in &parser.ops {
+            let (pop, push) = op.stack_effect();
+            depth = depth - pop + push;
+            if depth > MAX_STACK {
+                return Err(ProfileError::JsonParsing(format!(
+                    "Expression `{source}` is nested too deeply, maximum stack is {MAX_STACK}"
+                )));
+            }
+        }
+
+        Ok(Self { ops: parser.ops })
+    }
+
+    pub fn evaluate(&self, inputs: &DynamicsInputs) -> f64 {
+        let mut stack = [0.0f64; MAX_STACK];
+        let mut len = 0usize;
+
+        for op in &self.ops {
+            let (pop, _) = op.stack_effect();
+            len -= pop;
+            let args = &stack[len..len + pop];
+            stack[len] = match *op {
+                Op::Const(v) => v,
+                Op::Var(i) => inputs.get(i),
+                Op::Neg => -args[0],
+                Op::Add => args[0] + args[1],
+                Op::Sub => args[0] - args[1],
+                Op::Mul => args[0] * args[1],
+                Op::Div => args[0] / args[1],
+                Op::Pow => args[0].powf(args[1]),
+                Op::Call(f) => match f {
+                    Function::Min => args[0].min(args[1]),
+                    Function::Max => args[0].max(args[1]),
+                    Function::Clamp => args[0].max(args[1]).min(args[2]),
+                    Function::Abs => args[0].abs(),
+                    Function::Sqrt => args[0].sqrt(),
+                    Function::Exp => args[0].exp(),
+                    Function::Ln => args[0].ln(),
+                    Function::Sin => args[0].sin(),
+                    Function::Cos => args[0].cos(),
+                },
+            };
+            len += 1;
+        }
+
+        stack[0]
+    }
+}
+
+// Recursive descent parser emitting postfix operations
+//   expr    = term (('+' | '-') term)*
+//   term    = unary (('*' | '/') unary)*
+//   unary   = '-' unary | power
+//   power   = primary ('^' unary)?
+//   primary = number | variable | function '(' expr (',' expr)* ')' | '(' expr ')'
+struct Parser<'a> {
+    src: &'a [u8],
+    pos: usize,
+    depth: usize,
+    ops: Vec<Op>,
+}
+
+impl Parser<'_> {
+    fn error(&self, msg: &str) -> ProfileError {
+        ProfileError::JsonParsing(format!(
+            "Invalid expression at position {}: {msg}",
+            self.pos
+        ))
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.pos < self.src.len() && self.src[self.pos].is_ascii_whitespace() {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_whitespace();
+        self.src.get(self.pos).copied()
+    }
+
+    fn expect(&mut self, c: u8) -> Result<(), ProfileError> {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected `{}`", c as char)))
+        }
+    }
+
+    fn expr(&mut self) -> Result<(), ProfileError> {
+        self.term()?;
+        while let Some(c @ (b'+' | b'-')) = self.peek() {
+            self.pos += 1;
+            self.term()?;
+            self.ops.push(if c == b'+' { Op::Add } else { Op::Sub });
+        }
+        Ok(())
+    }
+
+    fn term(&mut self) -> Result<(), ProfileError> {
+        self.unary()?;
+        while let Some(c @ (b'*' | b'/')) = self.peek() {
+            self.pos += 1;
+            self.unary()?;
+            self.ops.push(if c == b'*' { Op::Mul } else { Op::Div });
+        }
+        Ok(())
+    }
+
+    // Negation, exponents, parentheses and function arguments all recurse through here
+    fn unary(&mut self) -> Result<(), ProfileError> {
+        if self.depth == MAX_DEPTH {
+            return Err(self.error(&format!(
+                "nested too deeply, maximum depth is {MAX_DEPTH}"
+            )));
+        }
+        self.depth += 1;
+        let result = if self.peek() == Some(b'-') {
+            self.pos += 1;
+            self.unary().map(|_| self.ops.push(Op::Neg))
+        } else {
+            self.power()
+        };
+        self.depth -= 1;
+        result
+    }
+
+    fn power(&mut self) -> Result<(), ProfileError> {
+        self.primary()?;
+        if self.peek() == Some(b'^') {
+            self.pos += 1;
+            self.unary()?;
+            self.ops.push(Op::Pow);
+        }
+        Ok(())
+    }
+
+    fn primary(&mut self) -> Result<(), ProfileError> {
+        match self.peek() {
+            Some(b'(') => {
+                self.pos += 1;
+                self.expr()?;
+                self.expect(b')')
+            }
+            Some(c) if c.is_ascii_digit() || c == b'.' => self.number(),
+            Some(c) if c.is_ascii_alphabetic() || c == b'_' => self.identifier(),
+            Some(_) => Err(self.error("unexpected character")),
+            None => Err(self.error("unexpected end of expression")),
+        }
+    }
+
+    fn number(&mut self) -> Result<(), ProfileError> {
+        let start = self.pos;
+        while self.pos < self.src.len()
+            && (self.src[self.pos].is_ascii_digit() || self.src[self.pos] == b'.')
+        {
+            self.pos += 1;
+        }
+        let text = std::str::from_utf8(&self.src[start..self.pos]).unwrap();
+        let v = text
+            .parse::<f64>()
+            .map_err(|_| self.error(&format!("invalid number `{text}`")))?;
+        self.ops.push(Op::Const(v));
+        Ok(())
+    }
+
+    fn identifier(&mut self) -> Result<(), ProfileError> {
+        let start = self.pos;
+        while self.pos < self.src.len()
+            && (self.src[self.pos].is_ascii_alphanumeric() || self.src[self.pos] == b'_')
+        {
+            self.pos += 1;
+        }
+        let name = std::str::from_utf8(&self.src[start..self.pos]).unwrap();
+
+        if let Some(f) = Function::from_name(name) {
+            self.expect(b'(')?;
+            self.expr()?;
+            for _ in 1..f.arity() {
+                self.expect(b',')?;
+                self.expr()?;
+            }
+            self.expect(b')')?;
+            self.ops.push(Op::Call(f));
+            return Ok(());
+        }
+
+        let var = match name {
+            "t" | "time" => InputType::Time,
+            "stage_time" => InputType::StageTime,
+            "piston_position" => InputType::PistonPosition,
+            "weight" => InputType::Weight,
+            "pressure" => InputType::Pressure,
+            "flow" => InputType::Flow,
+            "temperature" => InputType::Temperature,
+            "volume" => InputType::Volume,
+            x => return Err(self.error(&format!("unknown name `{x}`"))),
+        };
+        self.ops.push(Op::Var(var));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(source: &str) -> f64 {
+        Expression::compile(source)
+            .unwrap()
+            .evaluate(&DynamicsInputs::default())
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(eval("1 + 2 * 3"), 7.0);
+        assert_eq!(eval("(1 + 2) * 3"), 9.0);
+        assert_eq!(eval("8 / 4 / 2"), 1.0);
+        assert_eq!(eval("10 - 4 - 3"), 3.0);
+        assert_eq!(eval("2 * 3 ^ 2"), 18.0);
+        assert_eq!(eval("2 ^ 3 ^ 2"), 512.0);
+    }
+
+    #[test]
+    fn negation_binds_looser_than_power() {
+        assert_eq!(eval("-2^2"), -4.0);
+        assert_eq!(eval("(-2)^2"), 4.0);
+        assert_eq!(eval("2^-1"), 0.5);
+        assert_eq!(eval("--3"), 3.0);
+    }
+
+    #[test]
+    fn functions_and_variables() {
+        let inputs = DynamicsInputs {
+            stage_time: 2.0,
+            pressure: 9.0,
+            ..Default::default()
+        };
+        let e = Expression::compile("clamp(stage_time * 4, 1, min(pressure, 6))").unwrap();
+        assert_eq!(e.evaluate(&inputs), 6.0);
+        assert_eq!(eval("max(abs(-3), sqrt(4))"), 3.0);
+    }
+
+    #[test]
+    fn arity_errors() {
+        for source in ["min(1)", "min(1, 2, 3)", "abs()", "clamp(1, 2)", "sqrt 4"] {
+            assert!(Expression::compile(source).is_err(), "{source}");
+        }
+    }
+
+    #[test]
+    fn trailing_input() {
+        for source in ["1 2", "1)", "t t", "2 * 3 ,"] {
+            assert!(Expression::compile(source).is_err(), "{source}");
+        }
+    }
+
+    #[test]
+    fn unknown_names_and_empty_input() {
+        for source in ["", "foo", "1 +", "(1", "1 $ 2"] {
+            assert!(Expression::compile(source).is_err(), "{source}");
+        }
+    }
+
+    #[test]
+    fn deep_nesting_is_rejected() {
+        let negations = format!("{}1", "-".repeat(200_000));
+        assert!(Expression::compile(&negations).is_err());
+
+        let parens = format!("{}1{}", "(".repeat(200_000), ")".repeat(200_000));
+        assert!(Expression::compile(&parens).is_err());
+
+        let powers = format!("2{}", "^2".repeat(200_000));
+        assert!(Expression::compile(&powers).is_err());
+
+        let calls = format!("{}1{}", "abs(".repeat(200_000), ")".repeat(200_000));
+        assert!(Expression::compile(&calls).is_err());
+
+        // Reasonable nesting still compiles
+        assert_eq!(eval("((((1 + 2))))"), 3.0);
+    }
+}
@@ -3,7 +3,7 @@ use crate::sensor::SensorState;
 use json::object::Object;
 use json::JsonValue;
 use std::fmt::Formatter;
-use std::time::{Duration, SystemTime};
+use std::time::Duration;
 
 #[derive(Clone, Copy, Eq, PartialEq)]
 pub struct ExitTrigger {
@@ -93,23 +93,16 @@ impl ExitTrigger {
     pub fn check_cond<T: SensorState>(
         &self,
         input: &crate::sensor::Driver<T>,
-        stage_timestamp: SystemTime,
-        profile_timestamp: SystemTime,
+        now: Duration,
+        stage_timestamp: Duration,
+        profile_timestamp: Duration,
     ) -> bool {
         // this value = lhs, extern input = rhs
         let lhs = match self.exit_type() {
             ExitType::Pressure => input.sensor_data().water_pressure(),
             ExitType::Flow => input.sensor_data().water_flow(),
-            ExitType::TimeRelative => SystemTime::now()
-                .duration_since(stage_timestamp)
-                .or_else(|e| Ok::<Duration, ()>(e.duration()))
-                .unwrap()
-                .as_secs_f64(),
-            ExitType::TimeAbsolute => SystemTime::now()
-                .duration_since(profile_timestamp)
-                .or_else(|e| Ok::<Duration, ()>(e.duration()))
-                .unwrap()
-                .as_secs_f64(),
+            ExitType::TimeRelative => now.saturating_sub(stage_timestamp).as_secs_f64(),
+            ExitType::TimeAbsolute => now.saturating_sub(profile_timestamp).as_secs_f64(),
             ExitType::Weight => input.sensor_data().weight(),
             ExitType::PistonPosition => input.sensor_data().piston_position(),
             ExitType::Button => {
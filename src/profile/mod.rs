@@ -1,5 +1,5 @@
 pub mod dynamics;
-mod exit_trigger;
+pub mod exit_trigger;
 pub mod profile_definition;
 mod stage;
 mod types;
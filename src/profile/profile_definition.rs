@@ -1,5 +1,5 @@
-use super::{ProfileError, StageLog, Temp, Weight};
-use crate::profile::dynamics::{ControlType, Dynamics, Limit};
+use super::{ProfileError, RampRates, StageLog, Temp, Weight};
+use crate::profile::dynamics::{ControlType, Dynamics, Limit, OutputReference, Setpoint};
 use crate::profile::exit_trigger::{ExitComparison, ExitTrigger, ExitType};
 use crate::profile::stage::Stage;
 use json::object::Object;
@@ -8,12 +8,19 @@ use std::collections::{BTreeMap, HashMap};
 
 #[derive(Debug)]
 pub struct Profile {
+    id: Option<String>,
+    name: Option<String>,
     //start_time: SystemTime,
     starting_temp: Temp,
     target_weight: Weight,
     wait_after_heating: bool,
     auto_purge: bool,
 
+    // Applied whenever the current stage sets no limit of the same type
+    default_limits: Vec<Limit>,
+    ramp_rates: Option<RampRates>,
+    transition_blend: Option<f64>,
+
     //stages: *const Stage,
     stages: Vec<Stage>,
     //stage_log: *const StageLog,
@@ -21,6 +28,14 @@ pub struct Profile {
 }
 
 impl Profile {
+    pub fn get_id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+
+    pub fn get_name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
     pub fn get_starting_temp(&self) -> Temp {
         self.starting_temp
     }
@@ -33,6 +48,18 @@ impl Profile {
         self.wait_after_heating
     }
 
+    pub fn get_default_limits(&self) -> &[Limit] {
+        &self.default_limits
+    }
+
+    pub fn get_ramp_rates(&self) -> Option<&RampRates> {
+        self.ramp_rates.as_ref()
+    }
+
+    pub fn get_transition_blend(&self) -> Option<f64> {
+        self.transition_blend
+    }
+
     pub fn get_stages(&self) -> &[Stage] {
         &self.stages
     }
@@ -61,6 +88,16 @@ impl TryFrom<&Object> for Profile {
     type Error = ProfileError;
 
     fn try_from(e: &Object) -> Result<Self, Self::Error> {
+        let id = e
+            .get("id")
+            .map(|v| v.as_str().ok_or(ProfileError::unexpected_type("string")))
+            .transpose()?
+            .map(str::to_string);
+        let name = e
+            .get("name")
+            .map(|v| v.as_str().ok_or(ProfileError::unexpected_type("string")))
+            .transpose()?
+            .map(str::to_string);
         //let temperature: Temp,
         let target_weight = e
             .get("final_weight")
@@ -83,6 +120,9 @@ impl TryFrom<&Object> for Profile {
             .ok_or(ProfileError::no_name("temperature"))?
             .ok_or(ProfileError::unexpected_type("f64"))?;
 
+        let default_limits = parse_limits(e.get("limits"))?;
+        let (ramp_rates, transition_blend) = parse_transition(e)?;
+
         let stages: BTreeMap<u8, Stage> = {
             let stage_json = match e.get("stages").ok_or(ProfileError::no_name("stages"))? {
                 JsonValue::Array(arr) => arr,
@@ -97,16 +137,110 @@ impl TryFrom<&Object> for Profile {
             .collect();
 
         Ok(Self {
+            id,
+            name,
             target_weight: Weight::from(target_weight),
             wait_after_heating,
             auto_purge,
             starting_temp: Temp::from(temperature),
+            default_limits,
+            ramp_rates,
+            transition_blend,
             stages: stages.into_values().collect(),
             stage_log,//: stage_log.into_values().collect(),
         })
     }
 }
 
+fn parse_limits(value: Option<&JsonValue>) -> Result<Vec<Limit>, ProfileError> {
+    match value {
+        Some(JsonValue::Array(arr)) => arr
+            .iter()
+            .map(Limit::try_from)
+            .collect::<Result<Vec<_>, ProfileError>>(),
+        Some(_) => Err(ProfileError::unexpected_type("array")),
+        None => Ok(vec![]),
+    }
+}
+
+/// Secondary outputs driven next to the stage's main control. Ceilings cap a measured value and
+/// end up as limits, the temperature target ends up as the stage temperature
+fn parse_outputs(
+    value: Option<&JsonValue>,
+) -> Result<(Vec<Limit>, Option<Setpoint>), ProfileError> {
+    let outputs = match value {
+        Some(JsonValue::Array(arr)) => arr,
+        Some(_) => return Err(ProfileError::unexpected_type("array")),
+        None => return Ok((vec![], None)),
+    };
+
+    let mut ceilings = vec![];
+    let mut temperature = None;
+    for output in outputs {
+        let o = match output {
+            JsonValue::Object(o) => o,
+            _ => return Err(ProfileError::unexpected_type("object")),
+        };
+        let kind = o
+            .get("type")
+            .ok_or(ProfileError::no_name("type"))?
+            .as_str()
+            .ok_or(ProfileError::unexpected_type("string"))?;
+        let role = match o.get("role") {
+            Some(r) => r.as_str().ok_or(ProfileError::unexpected_type("string"))?,
+            None if kind == "temperature" => "target",
+            None => "ceiling",
+        };
+        match (role, kind) {
+            ("ceiling", _) => ceilings.push(Limit::try_from(o)?),
+            ("target", "temperature") => {
+                if temperature.is_some() {
+                    return Err(ProfileError::JsonParsing(
+                        "Only one temperature target output is allowed".to_string(),
+                    ));
+                }
+                let setpoint = match (o.get("value"), o.get("dynamics")) {
+                    (Some(v), None) | (None, Some(v)) => Setpoint::try_from(v)?,
+                    (Some(_), Some(_)) => {
+                        return Err(ProfileError::JsonParsing(
+                            "Outputs take either a `value` or `dynamics`, not both".to_string(),
+                        ))
+                    }
+                    (None, None) => return Err(ProfileError::no_name("value")),
+                };
+                temperature = Some(setpoint);
+            }
+            ("target", x) => {
+                return Err(ProfileError::Name(format!(
+                    "Only temperature can be a secondary target, the stage type drives `{x}`"
+                )))
+            }
+            (x, _) => {
+                return Err(ProfileError::Name(format!(
+                    "No valid value for output role, got `{x}`"
+                )))
+            }
+        }
+    }
+
+    Ok((ceilings, temperature))
+}
+
+fn parse_transition(value: &Object) -> Result<(Option<RampRates>, Option<f64>), ProfileError> {
+    let ramp_rates = value.get("ramp_rates").map(RampRates::try_from).transpose()?;
+    let transition_blend = match value.get("transition_blend").map(|v| v.as_f64()) {
+        Some(Some(blend)) if blend > 0.0 => Some(blend),
+        Some(Some(_)) => {
+            return Err(ProfileError::JsonParsing(
+                "Transition blend must be greater than 0".to_string(),
+            ))
+        }
+        Some(None) => return Err(ProfileError::unexpected_type("f64")),
+        None => None,
+    };
+    Ok((ramp_rates, transition_blend))
+}
+
 fn parse_stage(value: &[JsonValue]) -> Result<BTreeMap<u8, Stage>, ProfileError> {
     let names: HashMap<&str, u8> = value
         .iter()
@@ -150,21 +284,36 @@ fn parse_stage(value: &[JsonValue]) -> Result<BTreeMap<u8, Stage>, ProfileError>
             let dynamics_json = v.get("dynamics").ok_or(ProfileError::no_name("dynamics"))?;
             Dynamics::try_from(dynamics_json)?
         };
+        if control_type == ControlType::Power
+            && dynamics.output_reference() != OutputReference::Absolute
+        {
+            return Err(ProfileError::JsonParsing(format!(
+                "Stage `{name}` controls power, its dynamics can not be relative to the entry value"
+            )));
+        }
 
-        let limits = {
-            if let Some(limit_json) = v.get("limits") {
-                match limit_json {
-                    JsonValue::Array(arr) => {
-                        arr.iter()
-                            .map(Limit::try_from)
-                            .collect::<Result<Vec<_>, ProfileError>>()?
-                    }
-                    _ => return Err(ProfileError::unexpected_type("array")),
-                }
-            } else {
-                vec![]
+        let mut limits = parse_limits(v.get("limits"))?;
+        let (ramp_rates, transition_blend) = parse_transition(v)?;
+        let mut temperature = v.get("temperature").map(Setpoint::try_from).transpose()?;
+
+        let (ceilings, temperature_output) = parse_outputs(v.get("outputs"))?;
+        limits.extend(ceilings);
+        if temperature_output.is_some() {
+            if temperature.is_some() {
+                return Err(ProfileError::JsonParsing(format!(
+                    "Stage `{name}` sets its temperature both in `temperature` and `outputs`"
+                )));
             }
-        };
+            temperature = temperature_output;
+        }
+        for (i, l) in limits.iter().enumerate() {
+            if limits[..i].iter().any(|o| o.kind() == l.kind()) {
+                return Err(ProfileError::JsonParsing(format!(
+                    "Stage `{name}` has more than one {:?} limit",
+                    l.kind()
+                )));
+            }
+        }
         let exit_triggers = {
             let triggers = match v
                 .get("exit_triggers")
@@ -204,9 +353,269 @@ fn parse_stage(value: &[JsonValue]) -> Result<BTreeMap<u8, Stage>, ProfileError>
 
         out.insert(
             *(names.get(name).unwrap()),
-            Stage::new(control_type, dynamics, exit_triggers, limits),
+            Stage::new(name, control_type, dynamics, exit_triggers, limits)
+                .with_transition(ramp_rates, transition_blend)
+                .with_temperature(temperature),
         );
     }
 
     Ok(out)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profile::dynamics::{DynamicsInputs, LimitType};
+    use json::object;
+
+    fn stage() -> JsonValue {
+        object! {
+            name: "a",
+            type: "pressure",
+            dynamics: {
+                points: [[0, 9]],
+                over: "time",
+                interpolation: "linear",
+            },
+            exit_triggers: [{ type: "time", value: 10, relative: true }],
+        }
+    }
+
+    fn profile(stages: Vec<JsonValue>) -> JsonValue {
+        object! {
+            name: "Test",
+            temperature: 93,
+            final_weight: 36,
+            stages: stages,
+        }
+    }
+
+    fn parse(value: &JsonValue) -> Result<Profile, ProfileError> {
+        Profile::try_from(value)
+    }
+
+    #[test]
+    fn transition_blend_must_be_positive() {
+        for blend in [JsonValue::from(0), JsonValue::from(-1.5), JsonValue::from("2")] {
+            let mut value = profile(vec![stage()]);
+            value["transition_blend"] = blend.clone();
+            assert!(parse(&value).is_err(), "profile blend {blend}");
+
+            let mut s = stage();
+            s["transition_blend"] = blend.clone();
+            assert!(parse(&profile(vec![s])).is_err(), "stage blend {blend}");
+        }
+
+        let mut s = stage();
+        s["transition_blend"] = 0.5.into();
+        let mut value = profile(vec![s]);
+        value["transition_blend"] = 2.into();
+        let parsed = parse(&value).unwrap();
+        assert_eq!(parsed.get_transition_blend(), Some(2.0));
+        assert_eq!(parsed.get_stages()[0].transition_blend(), Some(0.5));
+    }
+
+    #[test]
+    fn relative_stage_dynamics() {
+        let relative = |ctrl: &str, relative: JsonValue| {
+            let mut s = stage();
+            s["type"] = ctrl.into();
+            s["dynamics"]["relative"] = relative;
+            parse(&profile(vec![s]))
+        };
+        for (value, reference) in [
+            (JsonValue::from(true), OutputReference::Offset),
+            (JsonValue::from(false), OutputReference::Absolute),
+            (JsonValue::from("absolute"), OutputReference::Absolute),
+            (JsonValue::from("offset"), OutputReference::Offset),
+            (JsonValue::from("fraction"), OutputReference::Fraction),
+        ] {
+            let parsed = relative("flow", value.clone()).unwrap();
+            assert_eq!(
+                parsed.get_stages()[0].dynamics().output_reference(),
+                reference,
+                "relative {value}"
+            );
+        }
+        assert!(relative("pressure", "percent".into()).is_err());
+        assert!(relative("pressure", 1.into()).is_err());
+
+        // There is no measured power to be relative to
+        assert!(relative("power", "offset".into()).is_err());
+        assert!(relative("power", "absolute".into()).is_ok());
+
+        // Nor do limits have an entry value
+        let mut s = stage();
+        s["limits"] = json::array![{
+            type: "flow",
+            dynamics: { points: [[0, 1]], over: "time", interpolation: "linear", relative: true },
+        }];
+        assert!(parse(&profile(vec![s.clone()])).is_err());
+        s["limits"][0]["dynamics"]["relative"] = false.into();
+        assert!(parse(&profile(vec![s])).is_ok());
+    }
+
+    #[test]
+    fn limits_take_a_value_or_dynamics() {
+        let limited = |limit: JsonValue| {
+            let mut s = stage();
+            s["limits"] = json::array![limit];
+            parse(&profile(vec![s]))
+        };
+        let dynamics = json::object! {
+            points: [[0, 6], [1, 3]],
+            over: "stage_time",
+            interpolation: "linear",
+        };
+
+        let parsed = limited(json::object! { type: "flow", dynamics: dynamics.clone() }).unwrap();
+        let limit = &parsed.get_stages()[0].limits()[0];
+        assert_eq!(limit.kind(), LimitType::Flow);
+        assert!(matches!(limit.setpoint(), Setpoint::Dynamic(_)));
+        let inputs = |stage_time| DynamicsInputs {
+            stage_time,
+            ..Default::default()
+        };
+        assert_eq!(limit.value(&inputs(0.0)), 6.0);
+        assert_eq!(limit.value(&inputs(0.5)), 4.5);
+        assert_eq!(limit.value(&inputs(2.0)), 3.0);
+
+        let parsed = limited(json::object! { type: "pressure", value: 9.5 }).unwrap();
+        let limit = &parsed.get_stages()[0].limits()[0];
+        assert!(matches!(limit.setpoint(), Setpoint::Constant(v) if *v == 9.5));
+
+        assert!(limited(json::object! { type: "flow", value: 3, dynamics: dynamics }).is_err());
+        assert!(limited(json::object! { type: "flow" }).is_err());
+        assert!(limited(json::object! { type: "flow", value: "3" }).is_err());
+        assert!(limited(json::object! { type: "volume", value: 3 }).is_err());
+    }
+
+    #[test]
+    fn profile_default_limits() {
+        let mut value = profile(vec![stage()]);
+        value["limits"] = json::array![
+            { type: "pressure", value: 9 },
+            { type: "piston_speed", value: 5 },
+        ];
+        let parsed = parse(&value).unwrap();
+        let kinds: Vec<LimitType> = parsed.get_default_limits().iter().map(|l| l.kind()).collect();
+        assert_eq!(kinds, [LimitType::Pressure, LimitType::PistonSpeed]);
+        assert!(parse(&profile(vec![stage()])).unwrap().get_default_limits().is_empty());
+
+        value["limits"] = json::object! { type: "pressure", value: 9 };
+        assert!(parse(&value).is_err());
+        value["limits"] = json::array![{ type: "weight", value: 9 }];
+        assert!(parse(&value).is_err());
+    }
+
+    #[test]
+    fn stage_temperature() {
+        let with_temperature = |temperature: JsonValue| {
+            let mut s = stage();
+            s["temperature"] = temperature;
+            parse(&profile(vec![s]))
+        };
+        let inputs = |stage_time| DynamicsInputs {
+            stage_time,
+            ..Default::default()
+        };
+
+        let parsed = with_temperature(91.5.into()).unwrap();
+        let temperature = parsed.get_stages()[0].temperature().unwrap();
+        assert_eq!(temperature.value(&inputs(3.0)), 91.5);
+
+        let curve = json::object! {
+            points: [[0, 92], [10, 88]],
+            over: "stage_time",
+            interpolation: "linear",
+        };
+        let parsed = with_temperature(curve.clone()).unwrap();
+        let temperature = parsed.get_stages()[0].temperature().unwrap();
+        assert_eq!(temperature.value(&inputs(5.0)), 90.0);
+
+        assert!(parse(&profile(vec![stage()])).unwrap().get_stages()[0]
+            .temperature()
+            .is_none());
+        assert!(with_temperature("93".into()).is_err());
+        let mut relative = curve;
+        relative["relative"] = "offset".into();
+        assert!(with_temperature(relative).is_err());
+    }
+
+    #[test]
+    fn stage_outputs() {
+        let with_outputs = |ctrl: &str, outputs: JsonValue| {
+            let mut s = stage();
+            s["type"] = ctrl.into();
+            s["outputs"] = outputs;
+            parse(&profile(vec![s]))
+        };
+        let curve = json::object! {
+            points: [[0, 6], [10, 3]],
+            over: "stage_time",
+            interpolation: "linear",
+        };
+
+        // Measured values default to ceilings, the temperature to the target
+        let parsed = with_outputs(
+            "pressure",
+            json::array![
+                { type: "flow", dynamics: curve.clone() },
+                { type: "temperature", dynamics: curve.clone() },
+                { type: "temperature", role: "ceiling", value: 95 },
+                { type: "power", role: "ceiling", value: 80 },
+            ],
+        )
+        .unwrap();
+        let multi = &parsed.get_stages()[0];
+        let kinds: Vec<LimitType> = multi.limits().iter().map(|l| l.kind()).collect();
+        assert_eq!(
+            kinds,
+            [LimitType::Flow, LimitType::Temperature, LimitType::Power]
+        );
+        assert!(matches!(multi.limits()[0].setpoint(), Setpoint::Dynamic(_)));
+        assert!(matches!(multi.temperature(), Some(Setpoint::Dynamic(_))));
+
+        let duplicate_limit = {
+            let mut s = stage();
+            s["limits"] = json::array![{ type: "flow", value: 4 }];
+            s["outputs"] = json::array![{ type: "flow", value: 3 }];
+            s
+        };
+        let both_temperatures = {
+            let mut s = stage();
+            s["temperature"] = 92.into();
+            s["outputs"] = json::array![{ type: "temperature", value: 90 }];
+            s
+        };
+        for s in [duplicate_limit, both_temperatures] {
+            assert!(parse(&profile(vec![s])).is_err());
+        }
+
+        let mut relative = curve.clone();
+        relative["relative"] = true.into();
+        for outputs in [
+            json::array![{ type: "flow", value: 3 }, { type: "flow", role: "ceiling", value: 4 }],
+            json::array![{ type: "temperature", value: 90 }, { type: "temperature", value: 91 }],
+            json::array![{ type: "flow", role: "target", value: 3 }],
+            json::array![{ type: "flow", role: "floor", value: 3 }],
+            json::array![{ type: "flow", value: 3, dynamics: curve.clone() }],
+            json::array![{ type: "flow", dynamics: relative.clone() }],
+            json::array![{ type: "temperature", dynamics: relative.clone() }],
+            json::array![{ type: "weight", value: 3 }],
+            json::array![3],
+            json::object! { type: "flow", value: 3 },
+        ] {
+            assert!(with_outputs("pressure", outputs.clone()).is_err(), "{outputs}");
+        }
+
+        // Power stages take ceilings, but no relative dynamics
+        let ceiling = json::array![{ type: "pressure", value: 9 }];
+        assert!(with_outputs("power", ceiling.clone()).is_ok());
+        let mut s = stage();
+        s["type"] = "power".into();
+        s["dynamics"]["relative"] = "fraction".into();
+        s["outputs"] = ceiling;
+        assert!(parse(&profile(vec![s])).is_err());
+    }
+}
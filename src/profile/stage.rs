@@ -1,18 +1,74 @@
-use crate::profile::dynamics::{ControlType, Dynamics, Limit};
+use crate::profile::dynamics::{ControlType, Dynamics, Limit, Setpoint};
 use crate::profile::exit_trigger::ExitTrigger;
-use crate::profile::{Flow, Percent, Pressure};
-use std::time::SystemTime;
+use crate::profile::{Flow, Percent, Pressure, ProfileError};
+use json::JsonValue;
+use std::time::Duration;
+
+/// Maximum rate of change of each setpoint type, in bar/s, ml/s and %/s
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct RampRates {
+    pressure: Option<f64>,
+    flow: Option<f64>,
+    power: Option<f64>,
+    piston_position: Option<f64>,
+}
+
+impl RampRates {
+    pub fn get(&self, ctrl: ControlType) -> Option<f64> {
+        match ctrl {
+            ControlType::Pressure => self.pressure,
+            ControlType::Flow => self.flow,
+            ControlType::Power => self.power,
+            ControlType::PistonPosition => self.piston_position,
+        }
+    }
+}
+
+impl TryFrom<&JsonValue> for RampRates {
+    type Error = ProfileError;
+
+    fn try_from(value: &JsonValue) -> Result<Self, Self::Error> {
+        let o = match value {
+            JsonValue::Object(o) => o,
+            _ => return Err(ProfileError::unexpected_type("object")),
+        };
+        let rate = |name: &str| match o.get(name) {
+            Some(v) => match v.as_f64() {
+                Some(r) if r > 0.0 => Ok(Some(r)),
+                Some(_) => Err(ProfileError::JsonParsing(format!(
+                    "Ramp rate `{name}` must be greater than 0"
+                ))),
+                None => Err(ProfileError::unexpected_type("f64")),
+            },
+            None => Ok(None),
+        };
+
+        Ok(Self {
+            pressure: rate("pressure")?,
+            flow: rate("flow")?,
+            power: rate("power")?,
+            piston_position: rate("piston_position")?,
+        })
+    }
+}
 
 #[derive(Debug)]
 pub struct Stage {
+    name: String,
     control_type: ControlType,
     dynamics: Dynamics,
     exit_trigger: Vec<ExitTrigger>,
     limits: Vec<Limit>,
+    // Override the profile wide setpoint smoothing when set
+    ramp_rates: Option<RampRates>,
+    transition_blend: Option<f64>,
+    // Water temperature during the stage, the previous target is kept when unset
+    temperature: Option<Setpoint>,
 }
 
 impl Stage {
     pub(super) fn new(
+        name: &str,
         control_type: ControlType,
         dynamics: Dynamics,
         //exitTrigger_len: u8,
@@ -21,12 +77,35 @@ impl Stage {
         limits: Vec<Limit>,
     ) -> Self {
         Self {
+            name: name.to_string(),
             control_type,
             dynamics,
             exit_trigger,
             limits,
+            ramp_rates: None,
+            transition_blend: None,
+            temperature: None,
         }
     }
+
+    pub(super) fn with_temperature(mut self, temperature: Option<Setpoint>) -> Self {
+        self.temperature = temperature;
+        self
+    }
+
+    pub(super) fn with_transition(
+        mut self,
+        ramp_rates: Option<RampRates>,
+        transition_blend: Option<f64>,
+    ) -> Self {
+        self.ramp_rates = ramp_rates;
+        self.transition_blend = transition_blend;
+        self
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
     pub fn dynamics(&self) -> &Dynamics {
         &self.dynamics
     }
@@ -41,6 +120,19 @@ impl Stage {
     pub fn ctrl(&self) -> ControlType {
         self.control_type
     }
+
+    pub fn ramp_rates(&self) -> Option<&RampRates> {
+        self.ramp_rates.as_ref()
+    }
+
+    /// Seconds over which the setpoint moves from the previous stage's to this stage's dynamics
+    pub fn transition_blend(&self) -> Option<f64> {
+        self.transition_blend
+    }
+
+    pub fn temperature(&self) -> Option<&Setpoint> {
+        self.temperature.as_ref()
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -49,10 +141,11 @@ pub struct StageVariables {
     flow: Flow,
     pressure: Pressure,
     piston_pos: Percent,
-    timestamp: SystemTime,
+    // Time from the engine clock
+    timestamp: Duration,
 }
 impl StageVariables {
-    pub fn new(flow: Flow, pressure: Pressure, piston_pos: Percent, timestamp: SystemTime) -> Self {
+    pub fn new(flow: Flow, pressure: Pressure, piston_pos: Percent, timestamp: Duration) -> Self {
         Self {
             flow,
             pressure,
@@ -62,15 +155,76 @@ impl StageVariables {
     }
 
     #[allow(unused)]
-    pub fn get_timestamp(&self) -> &SystemTime {
+    pub fn get_timestamp(&self) -> &Duration {
         &self.timestamp
     }
+
+    pub fn get_flow(&self) -> Flow {
+        self.flow
+    }
+
+    pub fn get_pressure(&self) -> Pressure {
+        self.pressure
+    }
+
+    pub fn get_piston_pos(&self) -> Percent {
+        self.piston_pos
+    }
 }
 
+/// Why a stage was left
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StageExitReason {
+    Trigger,
+    Aborted,
+    /// Left early on request for the next stage
+    Skipped,
+    /// Left on request for another stage
+    Jumped,
+    /// The shot reached its final weight
+    FinalWeight,
+}
+
+impl StageExitReason {
+    pub fn name(&self) -> &'static str {
+        match self {
+            StageExitReason::Trigger => "trigger",
+            StageExitReason::Aborted => "aborted",
+            StageExitReason::Skipped => "skipped",
+            StageExitReason::Jumped => "jumped",
+            StageExitReason::FinalWeight => "final_weight",
+        }
+    }
+}
+
+/// Entry and exit of the latest visit of a stage. A stage entered again, by a trigger looping
+/// back or a jump, keeps its earlier visits oldest first
 #[derive(Debug, Default)]
 pub struct StageLog {
     entry: Option<StageVariables>,
     exit: Option<StageVariables>,
+    exit_reason: Option<StageExitReason>,
+    earlier_visits: Vec<StageVisit>,
+}
+
+/// A finished visit of a stage, the exit is missing only if the engine never logged one
+#[derive(Debug)]
+pub struct StageVisit {
+    entry: StageVariables,
+    exit: Option<StageVariables>,
+    exit_reason: Option<StageExitReason>,
+}
+
+impl StageVisit {
+    pub fn get_entry(&self) -> &StageVariables {
+        &self.entry
+    }
+    pub fn get_exit(&self) -> Option<&StageVariables> {
+        self.exit.as_ref()
+    }
+    pub fn get_exit_reason(&self) -> Option<StageExitReason> {
+        self.exit_reason
+    }
 }
 
 impl StageLog {
@@ -98,6 +252,28 @@ impl StageLog {
     pub fn put_exit_log(&mut self, vars: StageVariables) -> Option<StageVariables> {
         Self::put_log(&mut self.exit, vars)
     }
+    pub fn get_exit_reason(&self) -> Option<StageExitReason> {
+        self.exit_reason
+    }
+    pub fn put_exit_reason(&mut self, reason: StageExitReason) -> Option<StageExitReason> {
+        self.exit_reason.replace(reason)
+    }
+    pub fn earlier_visits(&self) -> &[StageVisit] {
+        &self.earlier_visits
+    }
+
+    /// Makes room for another visit of the stage, the latest one moves to the earlier visits
+    pub fn start_visit(&mut self) {
+        let exit = self.exit.take();
+        let exit_reason = self.exit_reason.take();
+        if let Some(entry) = self.entry.take() {
+            self.earlier_visits.push(StageVisit {
+                entry,
+                exit,
+                exit_reason,
+            });
+        }
+    }
 
     fn put_log(old: &mut Option<StageVariables>, new: StageVariables) -> Option<StageVariables> {
         let o = old.take();
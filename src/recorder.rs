@@ -0,0 +1,205 @@
+use crate::engine::ProfileState;
+use crate::profile::dynamics::{ControlType, LimitType};
+use crate::sensor::SensorState;
+use std::time::Duration;
+
+/// Every `SensorState` reading of a tick, plus the dispensed volume
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct SensorSample {
+    pub pressure: f64,        // bar
+    pub flow: f64,            // ml/s
+    pub weight: f64,          // g
+    pub water_temp: f64,      // °C
+    pub piston_position: f64, // %
+    pub piston_speed: f64,
+    pub volume: f64, // ml, integrated from the flow
+    pub cylinder_temperature: f64,
+    pub external_temperature_1: f64,
+    pub external_temperature_2: f64,
+    pub tube_temperature: f64,
+    pub plunger_temperature: f64,
+    pub predictive_temperature: f64,
+    pub stable_temperature: f64,
+    pub temperature_up: f64,
+    pub temperature_middle_up: f64,
+    pub temperature_middle_down: f64,
+    pub temperature_down: f64,
+    pub output_position: f64,
+    pub motor_encoder: f64,
+    pub has_water: bool,
+}
+
+impl SensorSample {
+    /// Names of the numeric readings, in the order of `values`
+    pub const NAMES: [&'static str; 20] = [
+        "pressure",
+        "flow",
+        "weight",
+        "water_temp",
+        "piston_position",
+        "piston_speed",
+        "volume",
+        "cylinder_temperature",
+        "external_temperature_1",
+        "external_temperature_2",
+        "tube_temperature",
+        "plunger_temperature",
+        "predictive_temperature",
+        "stable_temperature",
+        "temperature_up",
+        "temperature_middle_up",
+        "temperature_middle_down",
+        "temperature_down",
+        "output_position",
+        "motor_encoder",
+    ];
+
+    pub fn read<T: SensorState>(sensors: &T, volume: f64) -> Self {
+        Self {
+            pressure: sensors.water_pressure(),
+            flow: sensors.water_flow(),
+            weight: sensors.weight(),
+            water_temp: sensors.water_temp(),
+            piston_position: sensors.piston_position(),
+            piston_speed: sensors.piston_speed(),
+            volume,
+            cylinder_temperature: sensors.cylinder_temperature(),
+            external_temperature_1: sensors.external_temperature_1(),
+            external_temperature_2: sensors.external_temperature_2(),
+            tube_temperature: sensors.tube_temperature(),
+            plunger_temperature: sensors.plunger_temperature(),
+            predictive_temperature: sensors.predictive_temperature(),
+            stable_temperature: sensors.stable_temperature(),
+            temperature_up: sensors.temperature_up(),
+            temperature_middle_up: sensors.temperature_middle_up(),
+            temperature_middle_down: sensors.temperature_middle_down(),
+            temperature_down: sensors.temperature_down(),
+            output_position: sensors.output_position(),
+            motor_encoder: sensors.motor_encoder(),
+            has_water: sensors.has_water(),
+        }
+    }
+
+    /// The numeric readings, named by `NAMES`
+    pub fn values(&self) -> [f64; Self::NAMES.len()] {
+        [
+            self.pressure,
+            self.flow,
+            self.weight,
+            self.water_temp,
+            self.piston_position,
+            self.piston_speed,
+            self.volume,
+            self.cylinder_temperature,
+            self.external_temperature_1,
+            self.external_temperature_2,
+            self.tube_temperature,
+            self.plunger_temperature,
+            self.predictive_temperature,
+            self.stable_temperature,
+            self.temperature_up,
+            self.temperature_middle_up,
+            self.temperature_middle_down,
+            self.temperature_down,
+            self.output_position,
+            self.motor_encoder,
+        ]
+    }
+}
+
+/// Everything currently commanded on the driver
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Setpoints {
+    pub output: Option<(ControlType, f64)>,
+    pub temperature: Option<f64>,
+    pub limits: [Option<f64>; LimitType::ALL.len()],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ShotSample {
+    pub time: Duration,
+    pub state: ProfileState,
+    pub stage: u8,
+    pub sensors: SensorSample,
+    /// Dynamics input and sampled value, only while brewing
+    pub input: Option<f64>,
+    pub output: Option<f64>,
+    pub setpoints: Setpoints,
+}
+
+/// Per tick time series of a shot. The buffer is allocated up front and never grows, once full
+/// further samples are counted as dropped.
+#[derive(Debug, Clone)]
+pub struct ShotRecording {
+    samples: Vec<ShotSample>,
+    dropped: usize,
+}
+
+impl ShotRecording {
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            samples: Vec::with_capacity(capacity),
+            dropped: 0,
+        }
+    }
+
+    pub fn push(&mut self, sample: ShotSample) {
+        if self.samples.len() < self.samples.capacity() {
+            self.samples.push(sample);
+        } else {
+            self.dropped += 1;
+        }
+    }
+
+    /// Empties the buffer for the next shot, keeping its allocation
+    pub fn clear(&mut self) {
+        self.samples.clear();
+        self.dropped = 0;
+    }
+
+    pub fn samples(&self) -> &[ShotSample] {
+        &self.samples
+    }
+
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(time: u64) -> ShotSample {
+        ShotSample {
+            time: Duration::from_millis(time),
+            state: ProfileState::Brewing,
+            stage: 0,
+            sensors: SensorSample::default(),
+            input: None,
+            output: None,
+            setpoints: Setpoints::default(),
+        }
+    }
+
+    #[test]
+    fn full_buffer_counts_dropped_samples() {
+        let mut recording = ShotRecording::with_capacity(3);
+        let capacity = recording.samples.capacity();
+        for time in 0..capacity as u64 + 4 {
+            recording.push(sample(time));
+        }
+        assert_eq!(recording.samples().len(), capacity);
+        assert_eq!(recording.dropped(), 4);
+        // The oldest samples are kept
+        assert_eq!(
+            recording.samples().last().unwrap().time,
+            Duration::from_millis(capacity as u64 - 1)
+        );
+
+        recording.clear();
+        assert!(recording.samples().is_empty());
+        assert_eq!(recording.dropped(), 0);
+        assert_eq!(recording.samples.capacity(), capacity);
+    }
+}
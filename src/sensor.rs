@@ -1,4 +1,6 @@
+use crate::control::{ControlMode, PidConfig, SoftwareControl};
 use crate::profile::{Flow, Pressure, Temp, Weight};
+use std::time::Duration;
 
 pub trait SensorState {
     #![allow(unused)]
@@ -25,16 +27,70 @@ pub trait SensorState {
     fn has_water(&self) -> bool;
 }
 
+/// Who keeps the measured values within the stage limits
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+pub enum LimitEnforcement {
+    /// The hardware honours the limits sent through the `set_*_limit` calls
+    #[default]
+    Hardware,
+    /// The engine backs off power and piston position outputs itself. `gain` is the fraction of
+    /// the output removed per unit of relative overshoot of the limit
+    Software { gain: f64 },
+}
+
+impl LimitEnforcement {
+    /// Used under software control unless another enforcement is chosen
+    pub const DEFAULT_SOFTWARE: Self = Self::Software { gain: 0.5 };
+}
+
 #[derive(Default, Clone, Debug)]
 pub struct Driver<T: SensorState> {
     sensors: T,
+    limit_enforcement: LimitEnforcement,
+    control: ControlMode,
+    // Engine clock time of the current step, used by the software control loops
+    now: Duration,
+    // Final weight of the shot (g), 0 when there is none
+    target_weight: f64,
 }
 
 impl<T: SensorState> Driver<T> {
+    pub fn with_limit_enforcement(mut self, limit_enforcement: LimitEnforcement) -> Self {
+        self.limit_enforcement = limit_enforcement;
+        self
+    }
+
+    /// For hardware that only exposes motor power, pressure and flow targets are turned into
+    /// power commands by PID loops running on the sensor feedback. Such hardware cannot limit
+    /// pressure and flow either, so limits are enforced in software, with
+    /// `LimitEnforcement::DEFAULT_SOFTWARE` unless another software enforcement is set
+    pub fn with_software_control(mut self, pressure: PidConfig, flow: PidConfig) -> Self {
+        self.control = ControlMode::Software(SoftwareControl::new(pressure, flow));
+        self
+    }
+
+    pub fn update_time(&mut self, now: Duration) {
+        self.now = now;
+    }
+
     pub fn sensor_data(&self) -> &T {
         &self.sensors
     }
 
+    #[cfg(test)]
+    pub fn sensor_data_mut(&mut self) -> &mut T {
+        &mut self.sensors
+    }
+
+    pub fn limit_enforcement(&self) -> LimitEnforcement {
+        match (self.limit_enforcement, &self.control) {
+            (LimitEnforcement::Hardware, ControlMode::Software(_)) => {
+                LimitEnforcement::DEFAULT_SOFTWARE
+            }
+            (enforcement, _) => enforcement,
+        }
+    }
+
     pub fn get_button_gesture(&self, _source: &str, _gesture: &str) -> bool {
         false
     }
@@ -44,11 +100,14 @@ impl<T: SensorState> Driver<T> {
         hardware_connection::heating_finished()
     }
 
+    /// The hardware may end the shot itself, the scale reading is checked as well
     pub fn has_reached_final_weight(&self) -> bool {
         hardware_connection::has_reached_final_weight()
+            || (self.target_weight > 0.0 && self.sensors.weight() >= self.target_weight)
     }
 
-    pub fn set_target_weight(&self, set_point: Weight) {
+    pub fn set_target_weight(&mut self, set_point: Weight) {
+        self.target_weight = set_point.into();
         hardware_connection::set_target_weight(set_point)
     }
 
@@ -56,27 +115,95 @@ impl<T: SensorState> Driver<T> {
         hardware_connection::set_target_temperature(set_point)
     }
 
-    pub fn set_target_pressure(&self, set_point: Pressure) {
-        hardware_connection::set_target_pressure(set_point)
+    pub fn set_target_pressure(&mut self, set_point: Pressure) {
+        match &mut self.control {
+            ControlMode::Hardware => hardware_connection::set_target_pressure(set_point),
+            ControlMode::Software(c) => hardware_connection::set_target_power(c.pressure_to_power(
+                set_point.into(),
+                self.sensors.water_pressure(),
+                self.sensors.water_flow(),
+                self.now,
+            )),
+        }
+    }
+
+    /// Under software control the limit caps the power of the flow loop
+    pub fn set_pressure_limit(&mut self, set_point: Pressure) {
+        match &mut self.control {
+            ControlMode::Hardware => hardware_connection::set_pressure_limit(set_point),
+            ControlMode::Software(c) => c.set_pressure_limit(Some(set_point.into())),
+        }
+    }
+
+    pub fn clear_pressure_limit(&mut self) {
+        match &mut self.control {
+            ControlMode::Hardware => hardware_connection::clear_pressure_limit(),
+            ControlMode::Software(c) => c.set_pressure_limit(None),
+        }
+    }
+
+    pub fn set_target_flow(&mut self, set_point: Flow) {
+        match &mut self.control {
+            ControlMode::Hardware => hardware_connection::set_target_flow(set_point),
+            ControlMode::Software(c) => hardware_connection::set_target_power(c.flow_to_power(
+                set_point.into(),
+                self.sensors.water_flow(),
+                self.sensors.water_pressure(),
+                self.now,
+            )),
+        }
+    }
+
+    /// Under software control the limit caps the power of the pressure loop
+    pub fn set_flow_limit(&mut self, set_point: Flow) {
+        match &mut self.control {
+            ControlMode::Hardware => hardware_connection::set_flow_limit(set_point),
+            ControlMode::Software(c) => c.set_flow_limit(Some(set_point.into())),
+        }
+    }
+
+    pub fn clear_flow_limit(&mut self) {
+        match &mut self.control {
+            ControlMode::Hardware => hardware_connection::clear_flow_limit(),
+            ControlMode::Software(c) => c.set_flow_limit(None),
+        }
+    }
+
+    pub fn set_power_limit(&self, set_point: f64) {
+        hardware_connection::set_power_limit(set_point)
     }
 
-    pub fn set_pressure_limit(&self, set_point: Pressure) {
-        hardware_connection::set_pressure_limit(set_point)
+    pub fn clear_power_limit(&self) {
+        hardware_connection::clear_power_limit()
     }
 
-    pub fn set_target_flow(&self, set_point: Flow) {
-        hardware_connection::set_target_flow(set_point)
+    pub fn set_piston_speed_limit(&self, set_point: f64) {
+        hardware_connection::set_piston_speed_limit(set_point)
     }
 
-    pub fn set_flow_limit(&self, set_point: Flow) {
-        hardware_connection::set_flow_limit(set_point)
+    pub fn clear_piston_speed_limit(&self) {
+        hardware_connection::clear_piston_speed_limit()
     }
 
-    pub fn set_target_power(&self, set_point: f64) {
+    pub fn set_temperature_limit(&self, set_point: Temp) {
+        hardware_connection::set_temperature_limit(set_point)
+    }
+
+    pub fn clear_temperature_limit(&self) {
+        hardware_connection::clear_temperature_limit()
+    }
+
+    pub fn set_target_power(&mut self, set_point: f64) {
+        if let ControlMode::Software(c) = &mut self.control {
+            c.release();
+        }
         hardware_connection::set_target_power(set_point)
     }
 
-    pub fn set_target_piston_position(&self, set_point: f64) {
+    pub fn set_target_piston_position(&mut self, set_point: f64) {
+        if let ControlMode::Software(c) = &mut self.control {
+            c.release();
+        }
         hardware_connection::set_target_piston_position(set_point)
     }
 }
@@ -135,7 +262,7 @@ impl Default for DummySensorState {
 
 impl SensorState for DummySensorState {
     fn piston_position(&self) -> f64 {
-        println!("Piston pos: {}", *self.piston_position);
+        log::trace!(target: "sensor", "Piston pos: {}", *self.piston_position);
         *self.piston_position
     }
 
@@ -228,58 +355,126 @@ mod hardware_connection {
     }
 
     pub fn set_target_weight(set_point: Weight) {
-        println!(
+        log::debug!(
+            target: "hardware",
             "Setting target weight to {}",
             <_ as Into<f64>>::into(set_point)
         );
     }
 
     pub fn set_target_temperature(set_point: Temp) {
-        println!(
+        log::debug!(
+            target: "hardware",
             "Setting target temperature to {}",
             <_ as Into<f64>>::into(set_point)
         );
     }
 
     pub fn set_target_pressure(set_point: Pressure) {
-        println!(
+        log::debug!(
+            target: "hardware",
             "Setting target pressure to {}",
             <_ as Into<f64>>::into(set_point)
         );
     }
 
     pub fn set_pressure_limit(set_point: Pressure) {
-        println!(
+        log::debug!(
+            target: "hardware",
             "Setting target pressure limit to {}",
             <_ as Into<f64>>::into(set_point)
         );
     }
 
+    pub fn clear_pressure_limit() {
+        log::debug!(target: "hardware", "Clearing pressure limit");
+    }
+
     pub fn set_target_flow(set_point: Flow) {
-        println!(
+        log::debug!(
+            target: "hardware",
             "Setting target flow to {}",
             <_ as Into<f64>>::into(set_point)
         );
     }
 
     pub fn set_flow_limit(set_point: Flow) {
-        println!(
+        log::debug!(
+            target: "hardware",
             "Setting flow limit to {}",
             <_ as Into<f64>>::into(set_point)
         );
     }
 
+    pub fn clear_flow_limit() {
+        log::debug!(target: "hardware", "Clearing flow limit");
+    }
+
+    pub fn set_power_limit(set_point: f64) {
+        log::debug!(target: "hardware", "Setting power limit to {}", set_point);
+    }
+
+    pub fn clear_power_limit() {
+        log::debug!(target: "hardware", "Clearing power limit");
+    }
+
+    pub fn set_piston_speed_limit(set_point: f64) {
+        log::debug!(target: "hardware", "Setting piston speed limit to {}", set_point);
+    }
+
+    pub fn clear_piston_speed_limit() {
+        log::debug!(target: "hardware", "Clearing piston speed limit");
+    }
+
+    pub fn set_temperature_limit(set_point: Temp) {
+        log::debug!(
+            target: "hardware",
+            "Setting temperature limit to {}",
+            <_ as Into<f64>>::into(set_point)
+        );
+    }
+
+    pub fn clear_temperature_limit() {
+        log::debug!(target: "hardware", "Clearing temperature limit");
+    }
+
     pub fn set_target_power(set_point: f64) {
-        println!(
+        log::debug!(
+            target: "hardware",
             "Setting target power to {}",
             <_ as Into<f64>>::into(set_point)
         );
     }
 
     pub fn set_target_piston_position(set_point: f64) {
-        println!(
+        log::debug!(
+            target: "hardware",
             "Setting target piston position to {}",
             <_ as Into<f64>>::into(set_point)
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn software() -> Driver<DummySensorState> {
+        let pid = PidConfig::new(5.0, 20.0, 0.0);
+        Driver::default().with_software_control(pid, pid)
+    }
+
+    #[test]
+    fn software_control_enforces_limits_in_software() {
+        let hardware = Driver::<DummySensorState>::default();
+        assert_eq!(hardware.limit_enforcement(), LimitEnforcement::Hardware);
+        assert_eq!(
+            software().limit_enforcement(),
+            LimitEnforcement::DEFAULT_SOFTWARE
+        );
+
+        let custom = LimitEnforcement::Software { gain: 2.0 };
+        let driver = software().with_limit_enforcement(custom);
+        assert_eq!(driver.limit_enforcement(), custom);
+    }
+}